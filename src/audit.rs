@@ -0,0 +1,155 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{ErrorKind, LineWriter, Write},
+    time::SystemTime,
+};
+
+use anyhow::anyhow;
+use sha2::{Digest, Sha256};
+use zenoh::{key_expr::KeyExpr, sample::Sample};
+
+/// Hash the first chained record links to
+const GENESIS: [u8; 32] = [0; 32];
+
+/// Append-only trail of the payload changes on the audited keys
+pub struct AuditLog {
+    file: LineWriter<File>,
+    keys: Vec<KeyExpr<'static>>,
+    last_payloads: HashMap<String, [u8; 32]>,
+    /// Hash of the previous record when chaining
+    chain: Option<[u8; 32]>,
+}
+
+impl AuditLog {
+    pub fn open(path: &str, keys: Vec<KeyExpr<'static>>, chain: bool) -> anyhow::Result<Self> {
+        // A chain continues from the last record of an earlier run, only a new log starts one
+        let chain = chain
+            .then(|| match std::fs::read_to_string(path) {
+                Ok(existing) => last_record_hash(&existing)
+                    .ok_or_else(|| anyhow!("Audit log {path} does not end with a chained record")),
+                Err(err) if err.kind() == ErrorKind::NotFound => Ok(GENESIS),
+                Err(err) => Err(anyhow!(
+                    "Could not read audit log {path} to continue its chain with reason: {err}"
+                )),
+            })
+            .transpose()?;
+        let file = File::options()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|err| anyhow!("Could not open audit log {path} with reason: {err}"))?;
+
+        Ok(Self {
+            file: LineWriter::new(file),
+            keys,
+            last_payloads: HashMap::new(),
+            chain,
+        })
+    }
+
+    /// Write a record if the sample is on an audited key and its payload changed
+    pub fn record(&mut self, sample: &Sample) {
+        let key = sample.key_expr();
+        if !self.keys.iter().any(|audited| audited.includes(key)) {
+            return;
+        }
+        let payload_hash: [u8; 32] = Sha256::digest(sample.payload().to_bytes()).into();
+        if self.last_payloads.insert(key.to_string(), payload_hash) == Some(payload_hash) {
+            return;
+        }
+
+        let time = sample
+            .timestamp()
+            .map_or_else(SystemTime::now, |timestamp| {
+                timestamp.get_time().to_system_time()
+            });
+        let source = sample.timestamp().map_or_else(
+            || "-".to_string(),
+            |timestamp| timestamp.get_id().to_string(),
+        );
+        let mut line = format!(
+            "{}\t{key}\t{source}\t{}",
+            humantime::format_rfc3339_micros(time),
+            hex(&payload_hash)
+        );
+        if let Some(previous) = &mut self.chain {
+            let mut hasher = Sha256::new();
+            hasher.update(previous.as_slice());
+            hasher.update(line.as_bytes());
+            *previous = hasher.finalize().into();
+            line = format!("{line}\t{}", hex(previous));
+        }
+
+        if let Err(err) = writeln!(self.file, "{line}") {
+            diag!("Error while writing to audit log with reason: {err}");
+        }
+    }
+}
+
+fn last_record_hash(existing: &str) -> Option<[u8; 32]> {
+    let Some(line) = existing.lines().last() else {
+        return Some(GENESIS);
+    };
+    let hash = line.split('\t').nth(4)?;
+    let mut bytes = [0; 32];
+    for (byte, digits) in bytes.iter_mut().zip(hash.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(digits).ok()?, 16).ok()?;
+    }
+    (hash.len() == 64).then_some(bytes)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_log_starts_from_genesis() {
+        assert_eq!(last_record_hash(""), Some(GENESIS));
+    }
+
+    #[test]
+    fn chain_continues_from_the_last_record() {
+        let hash = [0xab; 32];
+        let log = format!(
+            "t\tk\t-\t{}\t{}\nt\tk\t-\t{}\t{}\n",
+            hex(&[1; 32]),
+            hex(&[2; 32]),
+            hex(&[3; 32]),
+            hex(&hash)
+        );
+        assert_eq!(last_record_hash(&log), Some(hash));
+    }
+
+    #[test]
+    fn unreadable_log_does_not_restart_the_chain() {
+        let dir = std::env::temp_dir().join(format!("zenoh-dump-{}-audit", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let new = dir.join("new.log");
+        let log = AuditLog::open(new.to_str().unwrap(), Vec::new(), true).unwrap();
+        assert_eq!(log.chain, Some(GENESIS));
+
+        let binary = dir.join("binary.log");
+        std::fs::write(&binary, b"\xff\xfe\n").unwrap();
+        assert!(AuditLog::open(binary.to_str().unwrap(), Vec::new(), true).is_err());
+        // Not chaining, the existing content is not read
+        assert!(AuditLog::open(binary.to_str().unwrap(), Vec::new(), false).is_ok());
+    }
+
+    #[test]
+    fn unchained_last_record_is_rejected() {
+        let chained = format!("t\tk\t-\t{}\t{}\n", hex(&[1; 32]), hex(&[2; 32]));
+        let log = format!("{chained}t\tk\t-\t{}\n", hex(&[3; 32]));
+        assert_eq!(last_record_hash(&log), None);
+        assert_eq!(
+            last_record_hash(&format!("{chained}t\tk\t-\t-\tabc\n")),
+            None
+        );
+    }
+}
@@ -1,5 +1,6 @@
 use anyhow::anyhow;
 use clap::Parser;
+use zenoh::Wait;
 
 #[derive(Parser, Debug)]
 #[command(version)]
@@ -15,9 +16,16 @@ async fn main() -> anyhow::Result<()> {
     let args = Cli::parse();
 
     println!("Opening Zenoh session");
-    let session = zenoh::open(zenoh::Config::default())
-        .await
-        .map_err(|err| anyhow!("Could not open zenoh session with reason: {err}"))?;
+    // Opening blocks until connected, so it runs on its own thread to stay interruptible
+    let (open_tx, open_rx) = tokio::sync::oneshot::channel();
+    std::thread::spawn(move || {
+        let _ = open_tx.send(zenoh::open(zenoh::Config::default()).wait());
+    });
+    let session = tokio::select! {
+        session = open_rx => session?,
+        _ = tokio::signal::ctrl_c() => return Err(anyhow!("Interrupted while connecting to zenoh")),
+    }
+    .map_err(|err| anyhow!("Could not open zenoh session with reason: {err}"))?;
 
     println!("Sending message on channel '{}'", args.channel);
     session
@@ -1,19 +1,79 @@
 use std::{
+    borrow::Cow,
+    cmp::Reverse,
+    collections::{HashMap, HashSet, VecDeque},
     fs::File,
-    io::Stdout,
-    sync::{Arc, Mutex},
-    time::Instant,
+    hash::{DefaultHasher, Hash, Hasher},
+    io::{BufWriter, LineWriter, Stdout, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant, SystemTime},
 };
 
 use anyhow::anyhow;
-use clap::Parser;
+use byteorder::NativeEndian;
+use clap::{Parser, ValueEnum};
+use futures::stream::{self, BoxStream, SelectAll, StreamExt};
 use pcap_file::{
     DataLink, Endianness,
-    pcap::{PcapHeader, PcapPacket, PcapWriter},
+    pcap::{PcapHeader, PcapPacket},
+};
+use tokio::{
+    signal,
+    sync::{mpsc, oneshot, watch},
+    task::JoinHandle,
 };
-use tokio::{signal, sync::mpsc};
 use tokio_util::sync::CancellationToken;
-use zenoh::sample::Sample;
+use zenoh::{
+    Wait,
+    bytes::ZBytes,
+    config::WhatAmI,
+    handlers::{FifoChannel, FifoChannelHandler, RingChannel, RingChannelHandler},
+    key_expr::KeyExpr,
+    pubsub::Subscriber,
+    qos::Priority,
+    query::{ConsolidationMode, QueryTarget},
+    sample::{Sample, SampleKind},
+};
+
+use crate::{
+    audit::AuditLog,
+    manifest::CaptureInfo,
+    stats::{RateTracker, Stats},
+};
+
+/// Write a line to stderr through the diagnostic helper
+macro_rules! diag {
+    ($($arg:tt)*) => {
+        crate::diagnostic(format_args!($($arg)*))
+    };
+}
+
+mod audit;
+mod manifest;
+mod stats;
+
+/// Whether diagnostics are prefixed with the time, set once from --log-timestamps
+static LOG_TIMESTAMPS: AtomicBool = AtomicBool::new(false);
+
+/// Bound on the distinct encodings counted by --encoding-summary, the rest is grouped together
+const MAX_TRACKED_ENCODINGS: usize = 64;
+
+/// Longest time output accumulated with --stdout-chunk waits before being written
+const STDOUT_CHUNK_FLUSH: Duration = Duration::from_millis(100);
+
+/// Same as the standard library default for `BufWriter`
+const DEFAULT_BUF_SIZE: usize = 8 * 1024;
+
+/// Smallest transport batch that still fits a sample header and some payload
+const MIN_BATCH_SIZE: i64 = 64;
+
+/// Upper bound on the number of distinct keys tracked for rates
+const MAX_TRACKED_KEYS: usize = 1024;
 
 #[derive(Parser, Debug)]
 #[command(version)]
@@ -36,6 +96,34 @@ struct Cli {
     #[arg(long, default_value = "false")]
     /// Start capturing
     capture: bool,
+    #[arg(long, default_value = "false")]
+    /// Print the resolved Zenoh config as JSON5 without opening a session
+    dump_config: bool,
+    #[arg(long, default_value = "false")]
+    /// Report which Zenoh features are compiled into this build and exit
+    zenoh_feature_report: bool,
+    #[arg(
+        long,
+        default_value = "false",
+        conflicts_with_all = [
+            "probe", "key_snaplen", "kinds", "channel_kinds", "skip_empty", "split_by_priority",
+            "callback_mode", "audit", "manifest", "tee", "text_log", "dedup_overlap", "fair_queue",
+            "single_shot", "payload_grep", "suppress_unchanged", "canonicalize_json",
+            "min_interval", "warmup", "verbose", "keepalive", "heartbeat_file",
+            "per_channel_timestamps", "strict_order", "encoding_summary",
+        ]
+    )]
+    /// Capture the full history stored for the channels instead of subscribing
+    history: bool,
+    #[arg(long, default_value = "100000")]
+    /// Maximum number of historical samples captured with --history
+    max_history: usize,
+    #[arg(long, default_value = "false")]
+    /// Scout the network and list the discovered Zenoh nodes before capturing
+    probe: bool,
+    #[arg(long, default_value = "3")]
+    /// Time in seconds spent scouting with --probe
+    probe_duration: u64,
     #[arg(long, default_value = "")]
     /// Set a capture filter
     extcap_capture_filter: String,
@@ -45,11 +133,207 @@ struct Cli {
     #[arg(long, default_value = "*")]
     /// Channels to listen upon
     channels: Vec<String>,
+    #[arg(long, default_value = "0")]
+    /// Skip this many leading bytes of every payload before writing it
+    payload_offset: usize,
+    #[arg(long, visible_alias = "head-bytes", conflicts_with = "tail_bytes")]
+    /// Only capture the first N bytes of every payload (after the offset)
+    snaplen: Option<usize>,
+    #[arg(long)]
+    /// Only capture the last N bytes of every payload
+    tail_bytes: Option<usize>,
+    #[arg(long, value_name = "KEYEXPR=BYTES", conflicts_with = "tail_bytes")]
+    /// Snaplen for samples on keys matching the key expression, the first match wins
+    key_snaplen: Vec<String>,
+    #[arg(long, value_enum, default_value = "all")]
+    /// Sample kinds to capture on keys without a --channel-kinds rule
+    kinds: Kinds,
+    #[arg(long, value_name = "KEYEXPR=KINDS")]
+    /// Sample kinds to capture on keys matching the key expression, the first match wins
+    channel_kinds: Vec<String>,
+    #[arg(long, default_value = "false", conflicts_with_all = ["snaplen", "tail_bytes", "key_snaplen", "tee", "skip_empty"])]
+    /// Write no payload bytes at all, only the original payload size of every sample
+    metadata_only: bool,
+    #[arg(long, default_value = "false")]
+    /// Do not write packets that end up without any payload bytes
+    skip_empty: bool,
+    #[arg(long, default_value = "false", requires = "fifo")]
+    /// Write every Zenoh priority band to its own pcap file next to the fifo
+    split_by_priority: bool,
+    #[arg(long, conflicts_with = "split_by_priority")]
+    /// Accumulate this many bytes of output before writing, flushed at least every 100ms
+    stdout_chunk: Option<usize>,
+    #[arg(long)]
+    /// User to authenticate with against a secured Zenoh router
+    auth_user: Option<String>,
+    #[arg(long, env = "ZENOH_DUMP_AUTH_PASSWORD", hide_env_values = true)]
+    /// Password to authenticate with (prefer the env var or --auth-password-file)
+    auth_password: Option<String>,
+    #[arg(long, conflicts_with = "auth_password")]
+    /// File containing the password to authenticate with
+    auth_password_file: Option<String>,
+    #[arg(long, visible_alias = "mtu", value_parser = clap::value_parser!(u16).range(MIN_BATCH_SIZE..))]
+    /// Zenoh transport batch size in bytes, which is Zenoh's MTU equivalent
+    batch_size: Option<u16>,
+    #[arg(long)]
+    /// Declare the subscriptions included in this key expression to the router as this key expression alone
+    aggregate_interests: Vec<String>,
+    #[arg(long, value_name = "PATH=VALUE")]
+    /// Set any key of the Zenoh config to a JSON5 value, applied after every other flag
+    zenoh_set: Vec<String>,
+    #[arg(long, default_value = "false")]
+    /// Push samples from the zenoh subscriber callback instead of polling a channel
+    callback_mode: bool,
+    #[arg(long, default_value = "1", value_parser = clap::value_parser!(u16).range(1..), conflicts_with = "callback_mode")]
+    /// Number of subscribers serviced by a single task
+    subscribers_per_task: u16,
+    #[arg(
+        long,
+        value_enum,
+        default_value = "fifo",
+        conflicts_with = "callback_mode"
+    )]
+    /// What the zenoh subscribers do when their own buffer is full
+    subscriber_handler: SubscriberHandler,
+    #[arg(long, default_value = "256", value_parser = clap::value_parser!(u64).range(1..), conflicts_with = "callback_mode")]
+    /// Number of samples the buffer of every zenoh subscriber holds
+    subscriber_handler_size: u64,
+    #[arg(long, value_enum, default_value = "queue")]
+    /// What to do with samples that arrive faster than the writer can keep up
+    backpressure_policy: BackpressurePolicy,
+    #[arg(long, default_value = "1024", value_parser = clap::value_parser!(u64).range(1..))]
+    /// Number of samples the writer queue holds with --backpressure-policy drop
+    queue_size: u64,
+    #[arg(long, value_enum, default_value = "drop")]
+    /// What to do with samples received while paused with SIGUSR1
+    pause_policy: PausePolicy,
+    #[arg(long, requires = "audit_log")]
+    /// Keep an audit trail of the payload changes on keys matching this key expression
+    audit: Vec<String>,
+    #[arg(long)]
+    /// Append-only file the audit trail is written to
+    audit_log: Option<String>,
+    #[arg(long, default_value = "false")]
+    /// Chain every audit record to the hash of the previous one
+    audit_chain: bool,
+    #[arg(long, requires = "fifo")]
+    /// Write the size and SHA-256 of every capture file to this JSON manifest when stopping
+    manifest: Option<String>,
+    #[arg(long)]
+    /// Republish every captured sample on a second Zenoh network reached through this endpoint
+    tee: Option<String>,
+    #[arg(long)]
+    /// Also write one line of text per captured sample to this file
+    text_log: Option<String>,
+    #[arg(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        default_value = "timestamp,key,size,encoding"
+    )]
+    /// Columns of the text log, in order
+    text_log_columns: Vec<TextColumn>,
+    #[arg(long, default_value = "false", conflicts_with = "single_shot")]
+    /// Capture samples matching several overlapping channels only once
+    dedup_overlap: bool,
+    #[arg(long, default_value = "30000")]
+    /// Time in ms a subscriber declaration may take before its channel is skipped
+    declare_timeout_ms: u64,
+    #[arg(long, default_value = "false")]
+    /// Service the queued samples round-robin per key instead of in arrival order
+    fair_queue: bool,
+    #[arg(long, default_value = "false", conflicts_with_all = ["warmup", "kinds", "channel_kinds", "payload_grep", "skip_empty"])]
+    /// Capture exactly one sample per channel and exit once every channel has fired
+    single_shot: bool,
+    #[arg(long, default_value = "2000")]
+    /// Window in ms in which a second ctrl-c forces an immediate exit during shutdown
+    force_exit_window_ms: u64,
+    #[arg(long)]
+    /// Only write samples whose payload contains this pattern, `\xNN` matches a raw byte
+    payload_grep: Option<String>,
+    #[arg(long, default_value = "false", requires = "payload_grep")]
+    /// Interpret --payload-grep as a regular expression over the payload bytes
+    payload_grep_regex: bool,
+    #[arg(long, default_value = "false")]
+    /// Drop samples whose payload is identical to the previous sample on the same key
+    suppress_unchanged: bool,
+    #[arg(long, default_value = "false")]
+    /// Rewrite JSON payloads with sorted keys and no whitespace, other payloads are kept as is
+    canonicalize_json: bool,
+    #[arg(long)]
+    /// Write at most one sample per key every this many milliseconds, keeping the latest
+    min_interval: Option<u64>,
+    #[arg(long, default_value = "0")]
+    /// Discard the samples received in the first this many seconds of the capture
+    warmup: u64,
+    #[arg(long, default_value = "false")]
+    /// Only accept canonical channels without any wildcards
+    strict_keyexpr: bool,
+    #[arg(long, short, default_value = "false")]
+    /// Print every captured sample to stderr
+    verbose: bool,
+    #[arg(long, default_value = "false")]
+    /// Prefix every diagnostic on stderr with the current time
+    log_timestamps: bool,
+    #[arg(long, default_value = "32")]
+    /// Number of payload bytes dumped per sample in verbose mode (0 for metadata only)
+    preview_bytes: usize,
+    #[arg(long, default_value = "0", requires = "verbose")]
+    /// Periodically print the N keys with the highest publish rate in verbose mode
+    top_keys: usize,
+    #[arg(long, default_value = "5")]
+    /// Sliding window in seconds over which key rates are computed
+    rate_window: u64,
+    #[arg(long)]
+    /// Periodically publish a heartbeat on this key and verify it shows up in the capture
+    keepalive: Option<String>,
+    #[arg(long, default_value = "10")]
+    /// Interval in seconds between keepalive heartbeats
+    keepalive_interval: u64,
+    #[arg(long, default_value = "2000")]
+    /// Time in ms a heartbeat has to appear in the capture before warning
+    keepalive_timeout_ms: u64,
+    #[arg(long)]
+    /// File rewritten by the writer loop while it makes progress, for an external watchdog
+    heartbeat_file: Option<String>,
+    #[arg(long, default_value = "10", value_parser = clap::value_parser!(u64).range(1..))]
+    /// Interval in seconds between two writes of --heartbeat-file
+    heartbeat_file_interval: u64,
+    #[arg(long, default_value = "false")]
+    /// Timestamp packets relative to the first sample seen on their own channel
+    per_channel_timestamps: bool,
+    #[arg(
+        long,
+        default_value = "false",
+        conflicts_with = "per_channel_timestamps"
+    )]
+    /// Debugging aid: panic when a packet is written with an earlier timestamp than the previous one
+    strict_order: bool,
+    #[arg(long, default_value = "false")]
+    /// Report the number of samples and bytes captured per encoding when stopping
+    encoding_summary: bool,
+    #[cfg(feature = "statsd")]
+    #[arg(long, conflicts_with = "history")]
+    /// Push capture metrics to a StatsD server at host:port
+    statsd: Option<String>,
+    #[cfg(feature = "statsd")]
+    #[arg(long, default_value = "10")]
+    /// Interval in seconds between StatsD pushes
+    statsd_interval: u64,
+    #[cfg(feature = "zstd")]
+    #[arg(long, value_enum, requires = "fifo", conflicts_with_all = ["split_by_priority", "stdout_chunk"])]
+    /// Compress the capture file
+    compress: Option<Compression>,
+    #[cfg(feature = "zstd")]
+    #[arg(long, default_value = "3", value_parser = clap::value_parser!(i32).range(1..=22))]
+    /// Compression level, from 1 (fastest) to 22 (smallest)
+    compress_level: i32,
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let args = Cli::parse();
+    LOG_TIMESTAMPS.store(args.log_timestamps, Ordering::Relaxed);
     if args.extcap_interfaces {
         extcap_interfaces();
         return Ok(());
@@ -59,8 +343,20 @@ async fn main() -> anyhow::Result<()> {
     } else if args.extcap_config {
         extcap_config(args);
         return Ok(());
+    } else if args.dump_config {
+        return dump_config(args);
+    } else if args.zenoh_feature_report {
+        zenoh_feature_report();
+        return Ok(());
+    } else if args.capture && args.history {
+        return history(args).await;
     } else if args.capture {
+        if args.probe {
+            probe(&args).await?;
+        }
         return capture(args).await;
+    } else if args.probe {
+        return probe(&args).await;
     }
 
     return Ok(());
@@ -87,156 +383,2772 @@ fn extcap_dlts(_arg: Cli) {
     println!("dlt {{number=147}}{{name=USER0}}{{display=Demo Implementation for Extcap}}");
 }
 
-async fn capture(args: Cli) -> anyhow::Result<()> {
-    let session = zenoh::open(zenoh::Config::default())
+fn zenoh_config(args: &Cli) -> anyhow::Result<zenoh::Config> {
+    let mut config = zenoh::Config::default();
+
+    let password = match &args.auth_password_file {
+        Some(path) => Some(
+            std::fs::read_to_string(path)
+                .map_err(|err| anyhow!("Could not read password file {path} with reason: {err}"))?
+                .trim_end_matches(['\r', '\n'])
+                .to_string(),
+        ),
+        None => args.auth_password.clone(),
+    };
+    match (&args.auth_user, password) {
+        (Some(user), Some(password)) => {
+            config
+                .insert_json5("transport/auth/usrpwd/user", &json5_string(user))
+                .map_err(|err| anyhow!("Could not set auth user with reason: {err}"))?;
+            config
+                .insert_json5("transport/auth/usrpwd/password", &json5_string(&password))
+                .map_err(|err| anyhow!("Could not set auth password with reason: {err}"))?;
+        }
+        (None, None) => {}
+        _ => {
+            return Err(anyhow!(
+                "Authentication requires both --auth-user and a password"
+            ));
+        }
+    }
+
+    if let Some(batch_size) = args.batch_size {
+        config
+            .insert_json5("transport/link/tx/batch_size", &batch_size.to_string())
+            .map_err(|err| anyhow!("Could not set batch size with reason: {err}"))?;
+    }
+
+    if !args.aggregate_interests.is_empty() {
+        config
+            .insert_json5(
+                "aggregation/subscribers",
+                &serde_json::Value::from(args.aggregate_interests.clone()).to_string(),
+            )
+            .map_err(|err| anyhow!("Could not set aggregated interests with reason: {err}"))?;
+    }
+
+    for entry in &args.zenoh_set {
+        let (path, value) = entry
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Zenoh setting {entry} is not of the form <path>=<value>"))?;
+        config
+            .insert_json5(path.trim_start_matches('/'), value)
+            .map_err(|err| anyhow!("Could not apply zenoh setting {entry} with reason: {err}"))?;
+    }
+
+    Ok(config)
+}
+
+/// Config of the tee session, connected to `endpoint` only so it does not join the captured network
+fn tee_config(endpoint: &str) -> anyhow::Result<zenoh::Config> {
+    let mut config = zenoh::Config::default();
+    config
+        .insert_json5("mode", &json5_string("client"))
+        .and_then(|_| {
+            config.insert_json5(
+                "connect/endpoints",
+                &format!("[{}]", json5_string(endpoint)),
+            )
+        })
+        .and_then(|_| config.insert_json5("scouting/multicast/enabled", "false"))
+        .map_err(|err| anyhow!("Could not configure tee endpoint {endpoint} with reason: {err}"))?;
+    Ok(config)
+}
+
+/// Republish the samples sent to the returned queue on `session` until the queue is closed
+fn spawn_tee(session: zenoh::Session) -> (mpsc::UnboundedSender<Sample>, JoinHandle<()>) {
+    let (tee_tx, mut tee_rx) = mpsc::unbounded_channel::<Sample>();
+    let token = tokio::spawn(async move {
+        let mut failed = 0usize;
+        while let Some(sample) = tee_rx.recv().await {
+            let key = sample.key_expr().clone();
+            let result = match sample.kind() {
+                SampleKind::Put => {
+                    session
+                        .put(key, sample.payload().clone())
+                        .encoding(sample.encoding().clone())
+                        .attachment(sample.attachment().cloned())
+                        .priority(sample.priority())
+                        .congestion_control(sample.congestion_control())
+                        .await
+                }
+                SampleKind::Delete => {
+                    session
+                        .delete(key)
+                        .attachment(sample.attachment().cloned())
+                        .priority(sample.priority())
+                        .congestion_control(sample.congestion_control())
+                        .await
+                }
+            };
+            if let Err(err) = result {
+                // Only the first failure is reported, the tee must not flood stderr
+                if failed == 0 {
+                    diag!("Could not republish on the tee session with reason: {err}");
+                }
+                failed += 1;
+            }
+        }
+        if failed > 0 {
+            diag!("Failed to republish {failed} samples on the tee session");
+        }
+    });
+    (tee_tx, token)
+}
+
+/// List the routers and peers that answer scouting within the probe duration
+async fn probe(args: &Cli) -> anyhow::Result<()> {
+    let receiver = zenoh::scout(WhatAmI::Router | WhatAmI::Peer, zenoh_config(args)?)
         .await
-        .map_err(|err| anyhow!("Could not open zenoh session with reason: {err}"))?;
+        .map_err(|err| anyhow!("Could not start scouting with reason: {err}"))?;
 
-    let cancel_token = CancellationToken::new();
+    let deadline = tokio::time::sleep(Duration::from_secs(args.probe_duration));
+    tokio::pin!(deadline);
+    let mut discovered = HashSet::new();
+    loop {
+        tokio::select! {
+            hello = receiver.recv_async() => {
+                let Ok(hello) = hello else { break };
+                if discovered.insert(hello.zid()) {
+                    diag!("Discovered {} {} at {:?}", hello.whatami(), hello.zid(), hello.locators());
+                }
+            }
+            _ = &mut deadline => break,
+        }
+    }
 
-    let (sink_tx, mut sink_rx) = mpsc::unbounded_channel();
+    if discovered.is_empty() {
+        diag!("No Zenoh nodes answered scouting, a capture will not see any data");
+    } else {
+        diag!("Discovered {} Zenoh nodes", discovered.len());
+    }
+    Ok(())
+}
 
-    // Setup all the channels
-    let mut join_tokens = Vec::with_capacity(args.channels.len());
-    for channel in args.channels {
-        let subscriber = session
-            .declare_subscriber(channel.clone())
-            .await
-            .map_err(|err| anyhow!("Could not open channel {channel} with reason: {err}"))?;
-        let cancel_token = cancel_token.clone();
-        let sink_tx = sink_tx.clone();
-        let join_token = tokio::spawn(async move {
-            loop {
-                tokio::select! {
-                    sample = subscriber.recv_async() => {
-                        match sample {
-                            Ok(sample) => {
-                                // Send sample to sink
-                                let _ = sink_tx.send(sample);
-                            }
-                            Err(err) => {
-                                // We have an error, report and quit
-                                println!("Error while listening on zenoh channel with reason: {err}");
-                                break
-                            }
-                        }
-                    }
-                    _ = cancel_token.cancelled() => {
-                        // Someone pressed ctrl_c, so quiting
-                        break
-                    }
+fn dump_config(args: Cli) -> anyhow::Result<()> {
+    let config = zenoh_config(&args)?;
+    let mut json = serde_json::from_str::<serde_json::Value>(&config.to_string())
+        .map_err(|err| anyhow!("Could not serialize zenoh config with reason: {err}"))?;
+    redact_secrets(&mut json);
+    println!("{}", serde_json::to_string_pretty(&json)?);
+
+    Ok(())
+}
+
+/// Zenoh features that decide which transports and options are usable
+const REPORTED_FEATURES: &[(&str, &str)] = &[
+    ("transport_tcp", "tcp/ endpoints"),
+    ("transport_udp", "udp/ endpoints"),
+    ("transport_tls", "tls/ endpoints"),
+    ("transport_quic", "quic/ endpoints"),
+    ("transport_ws", "ws/ endpoints"),
+    ("transport_unixsock-stream", "unixsock-stream/ endpoints"),
+    ("transport_unixpipe", "unixpipe/ endpoints"),
+    ("transport_serial", "serial/ endpoints"),
+    ("transport_vsock", "vsock/ endpoints"),
+    ("transport_multilink", "multiple links per transport"),
+    ("auth_usrpwd", "--auth-user and --auth-password"),
+    ("auth_pubkey", "public key authentication"),
+    ("shared-memory", "shared memory transport"),
+    ("stats", "transport statistics"),
+    ("unstable", "unstable Zenoh APIs"),
+];
+
+fn zenoh_feature_report() {
+    let enabled = zenoh::FEATURES
+        .split_whitespace()
+        .filter_map(|feature| feature.strip_prefix("zenoh/"))
+        .collect::<HashSet<_>>();
+    diag!("Zenoh features:");
+    for (feature, usage) in REPORTED_FEATURES {
+        let state = if enabled.contains(feature) {
+            "yes"
+        } else {
+            "no"
+        };
+        diag!("  {feature:<26} {state:<4} {usage}");
+    }
+    let statsd = if cfg!(feature = "statsd") {
+        "yes"
+    } else {
+        "no"
+    };
+    let zstd = if cfg!(feature = "zstd") { "yes" } else { "no" };
+    diag!("zenoh-dump features:");
+    diag!("  {:<26} {statsd:<4} --statsd", "statsd");
+    diag!("  {:<26} {zstd:<4} --compress zstd", "zstd");
+}
+
+/// Blank out passwords and inline key material in a serialized config
+fn redact_secrets(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                let secret = key == "password" || key.ends_with("_pem") || key.ends_with("_base64");
+                if secret && !value.is_null() {
+                    *value = "<redacted>".into();
+                } else {
+                    redact_secrets(value);
                 }
             }
+        }
+        serde_json::Value::Array(values) => values.iter_mut().for_each(redact_secrets),
+        _ => {}
+    }
+}
+
+fn json5_string(value: &str) -> String {
+    serde_json::Value::from(value).to_string()
+}
+
+/// Window of the payloads written, empty with --metadata-only
+fn payload_window(args: &Cli) -> PayloadWindow {
+    PayloadWindow {
+        offset: args.payload_offset,
+        head: if args.metadata_only {
+            Some(0)
+        } else {
+            args.snaplen
+        },
+        tail: args.tail_bytes,
+    }
+}
+
+/// Parse a `<keyexpr>=<bytes>` snaplen override
+fn parse_key_snaplen(spec: &str, strict: bool) -> anyhow::Result<(KeyExpr<'static>, usize)> {
+    let (key, snaplen) = spec
+        .rsplit_once('=')
+        .ok_or_else(|| anyhow!("Key snaplen {spec} is not of the form <keyexpr>=<bytes>"))?;
+    let snaplen = snaplen
+        .parse()
+        .map_err(|err| anyhow!("Key snaplen {spec} has an invalid size with reason: {err}"))?;
+    Ok((resolve_channel(key.to_string(), strict)?, snaplen))
+}
+
+/// Parse a `<keyexpr>=<kinds>` kind rule
+fn parse_channel_kinds(spec: &str, strict: bool) -> anyhow::Result<(KeyExpr<'static>, Kinds)> {
+    let (key, kinds) = spec
+        .rsplit_once('=')
+        .ok_or_else(|| anyhow!("Channel kinds {spec} is not of the form <keyexpr>=<kinds>"))?;
+    let kinds = Kinds::from_str(kinds, true)
+        .map_err(|err| anyhow!("Channel kinds {spec} has invalid kinds with reason: {err}"))?;
+    Ok((resolve_channel(key.to_string(), strict)?, kinds))
+}
+
+/// Turn a channel into a key expression, canonizing it unless `strict` is set
+fn resolve_channel(channel: String, strict: bool) -> anyhow::Result<KeyExpr<'static>> {
+    if !strict {
+        return KeyExpr::autocanonize(channel.clone()).map_err(|err| {
+            anyhow!("Channel {channel} is not a valid key expression with reason: {err}")
         });
+    }
 
-        join_tokens.push(join_token);
+    if channel.contains('*') {
+        return Err(anyhow!(
+            "Channel {channel} contains a wildcard, which is not allowed with --strict-keyexpr"
+        ));
     }
+    KeyExpr::try_from(channel.clone()).map_err(|err| {
+        anyhow!("Channel {channel} is not a canonical key expression with reason: {err}")
+    })
+}
 
-    // Setup the sink
-    let join_token = tokio::spawn({
-        let cancel_token = cancel_token.clone();
+/// Open a session that can be interrupted with ctrl-c while connecting
+///
+/// `zenoh::open` connects synchronously even when awaited and can hang on unreachable endpoints,
+/// so it runs on a detached thread that is simply abandoned when interrupted.
+async fn open_session(
+    config: zenoh::Config,
+    interrupted: &CancellationToken,
+) -> anyhow::Result<zenoh::Result<zenoh::Session>> {
+    let (open_tx, open_rx) = oneshot::channel();
+    std::thread::spawn(move || {
+        let _ = open_tx.send(zenoh::open(config).wait());
+    });
 
-        // Setup writer
-        let mut writer = FIFOWriter::new(args.fifo)?;
+    tokio::select! {
+        session = open_rx => {
+            session.map_err(|err| anyhow!("Could not open zenoh session with reason: {err}"))
+        }
+        _ = interrupted.cancelled() => Err(anyhow!("Interrupted while connecting to zenoh")),
+    }
+}
+
+/// Resolve `--channels`, refusing to run without any
+fn channels(args: &Cli) -> anyhow::Result<Vec<KeyExpr<'static>>> {
+    // Blank entries, e.g. from a trailing separator in a script, are skipped
+    let channels = args
+        .channels
+        .iter()
+        .map(|channel| channel.trim())
+        .filter(|channel| !channel.is_empty())
+        .collect::<Vec<_>>();
+    if channels.is_empty() {
+        return Err(anyhow!(
+            "No channels to listen upon, use --channels '**' to capture everything"
+        ));
+    }
+    channels
+        .into_iter()
+        .map(|channel| resolve_channel(channel.to_string(), args.strict_keyexpr))
+        .collect()
+}
+
+/// Open the session described by the flags
+async fn connect(args: &Cli, interrupted: &CancellationToken) -> anyhow::Result<zenoh::Session> {
+    let authenticated = args.auth_user.is_some();
+    open_session(zenoh_config(args)?, interrupted).await?.map_err(|err| {
+        if authenticated {
+            anyhow!(
+                "Could not open zenoh session with reason: {err} (check that the credentials are accepted by the router)"
+            )
+        } else {
+            anyhow!("Could not open zenoh session with reason: {err}")
+        }
+    })
+}
+
+/// Query the full history of every channel from the storages and write it ordered by timestamp
+async fn history(args: Cli) -> anyhow::Result<()> {
+    let channels = channels(&args)?;
+    let interrupted = spawn_interrupt();
+    let session = connect(&args, &interrupted).await?;
+
+    let mut samples = Vec::new();
+    'channels: for channel in &channels {
+        let replies = session
+            .get(format!("{channel}?_time=[..]"))
+            .consolidation(ConsolidationMode::None)
+            .target(QueryTarget::All)
+            .await
+            .map_err(|err| anyhow!("Could not query history of {channel} with reason: {err}"))?;
+        loop {
+            let reply = tokio::select! {
+                reply = replies.recv_async() => reply,
+                _ = interrupted.cancelled() => {
+                    diag!("Interrupted, writing the history received so far");
+                    break 'channels;
+                }
+            };
+            let Ok(reply) = reply else {
+                // Every storage replied
+                break;
+            };
+            match reply.into_result() {
+                Ok(sample) => samples.push(sample),
+                Err(err) => diag!("Storage replied with an error for {channel}: {err:?}"),
+            }
+            if samples.len() >= args.max_history {
+                diag!("Reached --max-history, the rest of the history is not captured");
+                break 'channels;
+            }
+        }
+    }
+
+    // Samples without a timestamp cannot be ordered and go first
+    let time = |sample: &Sample| {
+        sample.timestamp().map(|timestamp| {
+            timestamp
+                .get_time()
+                .to_system_time()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+        })
+    };
+    samples.sort_by_key(time);
+
+    let window = payload_window(&args);
+    let mut writer = single_writer(&args, window)?;
+    let count = samples.len();
+    for sample in samples {
+        let timestamp = time(&sample).unwrap_or_default();
+        writer.write_pcap(&sample, timestamp, window).await?;
+    }
+    writer.flush().await?;
+    diag!("Captured {count} historical samples");
+
+    Ok(())
+}
+
+/// Declare off the runtime and give up after `timeout`
+///
+/// Declarations resolve synchronously even when awaited, so a stalled router would otherwise
+/// block startup. A declaration that completes after the timeout is dropped, undeclaring it.
+async fn declare_with_timeout<T: Send + 'static>(
+    timeout: Duration,
+    interrupted: &CancellationToken,
+    declare: impl FnOnce() -> zenoh::Result<T> + Send + 'static,
+) -> anyhow::Result<Option<zenoh::Result<T>>> {
+    let (declared_tx, declared_rx) = oneshot::channel();
+    std::thread::spawn(move || {
+        let _ = declared_tx.send(declare());
+    });
 
+    tokio::select! {
+        declared = tokio::time::timeout(timeout, declared_rx) => Ok(declared.ok().and_then(Result::ok)),
+        _ = interrupted.cancelled() => Err(anyhow!("Interrupted while declaring the channels")),
+    }
+}
+
+/// Cancel the returned token on the first Ctrl-C
+///
+/// Listening for Ctrl-C replaces the default handler for the rest of the process, so a single
+/// listener is installed up front and every phase of the capture waits on its token.
+fn spawn_interrupt() -> CancellationToken {
+    let interrupted = CancellationToken::new();
+    tokio::spawn({
+        let interrupted = interrupted.clone();
         async move {
-            loop {
-                tokio::select! {
-                    sample = sink_rx.recv() => {
-                        match sample {
-                            Some(sample) => {
-                                // Output new sample
-                                let _ = writer.write_pcap(sample).await.inspect_err(|err| eprintln!("Error while writing to pcap with reason: {err}"));
-                            }
-                            None => {
-                                // Sink is up
-                                break
-                            }
-                        }
-                    }
-                    _ = cancel_token.cancelled() => {
-                        // We need to stop
-                        break
+            if signal::ctrl_c().await.is_ok() {
+                interrupted.cancel();
+            }
+        }
+    });
+    interrupted
+}
+
+async fn capture(args: Cli) -> anyhow::Result<()> {
+    let channels = channels(&args)?;
+
+    // Every flag is validated before connecting, so a typo does not cost a connection attempt
+    let keepalive = args
+        .keepalive
+        .clone()
+        .map(|key| resolve_channel(key, true))
+        .transpose()?;
+    if let Some(key) = &keepalive
+        && !channels.iter().any(|channel| channel.intersects(key))
+    {
+        diag!(
+            "Keepalive key {key} is not covered by any channel, heartbeats will never be captured"
+        );
+    }
+    let heartbeat_seen = Arc::new(AtomicU64::new(0));
+    let key_snaplens = args
+        .key_snaplen
+        .iter()
+        .map(|spec| parse_key_snaplen(spec, args.strict_keyexpr))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let channel_kinds = args
+        .channel_kinds
+        .iter()
+        .map(|spec| parse_channel_kinds(spec, args.strict_keyexpr))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let payload_grep = args
+        .payload_grep
+        .as_deref()
+        .map(|pattern| payload_grep(pattern, args.payload_grep_regex))
+        .transpose()?;
+    let text_log = args
+        .text_log
+        .as_deref()
+        .map(|path| TextLog::new(path, args.text_log_columns.clone()))
+        .transpose()?;
+    let audit = match &args.audit_log {
+        Some(path) if !args.audit.is_empty() => {
+            let keys = args
+                .audit
+                .iter()
+                .map(|key| resolve_channel(key.clone(), args.strict_keyexpr))
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            Some(AuditLog::open(path, keys, args.audit_chain)?)
+        }
+        _ => None,
+    };
+    #[cfg(feature = "statsd")]
+    let statsd = args
+        .statsd
+        .as_deref()
+        .map(stats::statsd::connect)
+        .transpose()?;
+
+    let interrupted = spawn_interrupt();
+    let session = connect(&args, &interrupted).await?;
+
+    let mut join_tokens = Vec::new();
+    let tee = match &args.tee {
+        Some(endpoint) => {
+            let session = open_session(tee_config(endpoint)?, &interrupted)
+                .await?
+                .map_err(|err| {
+                    anyhow!("Could not connect to tee endpoint {endpoint} with reason: {err}")
+                })?;
+            let (tee_tx, tee_token) = spawn_tee(session);
+            join_tokens.push(tee_token);
+            Some(tee_tx)
+        }
+        None => None,
+    };
+
+    let started = SystemTime::now();
+    let cancel_token = CancellationToken::new();
+    let channel_names = channels
+        .iter()
+        .map(|channel| channel.to_string())
+        .collect::<Vec<_>>();
+    let stats = Arc::new(Stats::new(channel_names.clone()));
+
+    let (sink_tx, sink_rx) = sink_queue(args.backpressure_policy, args.queue_size as usize);
+
+    // Setup all the channels
+    let single_shot = args.single_shot;
+    let force_exit_window = Duration::from_millis(args.force_exit_window_ms);
+    let pending_channels = Arc::new(AtomicUsize::new(channels.len()));
+    let mut subscriber_tokens = Vec::new();
+    let mut channel_states = Vec::with_capacity(channels.len());
+    let mut group = Vec::with_capacity(args.subscribers_per_task as usize);
+    warn_overlapping(&channels);
+    let declare_timeout = Duration::from_millis(args.declare_timeout_ms);
+    let mut undeclared = Vec::new();
+    let duplicates = Arc::new(AtomicU64::new(0));
+    let overloaded = Arc::new(AtomicU64::new(0));
+    // Channels whose subscriber was declared, skipped ones capture nothing to deduplicate against
+    let mut declared_channels = Vec::with_capacity(channels.len());
+    for (index, channel) in channels.iter().cloned().enumerate() {
+        let overlapping = if args.dedup_overlap {
+            overlapping_channels(&declared_channels, &channel)
+        } else {
+            Vec::new()
+        };
+        let state = Arc::new(ChannelState {
+            index,
+            channel: channel.to_string(),
+            overlapping,
+            duplicates: duplicates.clone(),
+            overloaded: overloaded.clone(),
+            sink_tx: Mutex::new(Some(sink_tx.clone())),
+            stats: stats.clone(),
+            single_shot,
+            pending_channels: pending_channels.clone(),
+            cancel_token: cancel_token.clone(),
+            fired: AtomicBool::new(false),
+        });
+        channel_states.push(state.clone());
+        let declare_err = |err| anyhow!("Could not open channel {channel} with reason: {err}");
+
+        let timed_out = |state: &ChannelState| {
+            diag!(
+                "Declaring channel {channel} timed out after {}ms, it is not captured",
+                declare_timeout.as_millis()
+            );
+            state.abandon();
+        };
+
+        if args.callback_mode {
+            // Samples are pushed straight from the zenoh callback into the sink
+            let declared = declare_with_timeout(declare_timeout, &interrupted, {
+                let session = session.clone();
+                let channel = channel.clone();
+                let state = state.clone();
+                move || {
+                    session
+                        .declare_subscriber(channel)
+                        .callback(move |sample| {
+                            state.on_sample(sample);
+                        })
+                        .wait()
+                }
+            })
+            .await?;
+            let Some(subscriber) = declared else {
+                timed_out(&state);
+                undeclared.push(channel.to_string());
+                continue;
+            };
+            let subscriber = subscriber.map_err(declare_err)?;
+            declared_channels.push(channel.clone());
+            let cancel_token = cancel_token.clone();
+            subscriber_tokens.push(tokio::spawn(async move {
+                cancel_token.cancelled().await;
+                drop(subscriber);
+                state.close();
+            }));
+        } else {
+            let declared = declare_with_timeout(declare_timeout, &interrupted, {
+                let session = session.clone();
+                let channel = channel.clone();
+                let handler = args.subscriber_handler;
+                let size = args.subscriber_handler_size as usize;
+                move || {
+                    let subscriber = session.declare_subscriber(channel);
+                    match handler {
+                        SubscriberHandler::Fifo => subscriber
+                            .with(FifoChannel::new(size))
+                            .wait()
+                            .map(ChannelSubscriber::Fifo),
+                        SubscriberHandler::Ring => subscriber
+                            .with(RingChannel::new(size))
+                            .wait()
+                            .map(ChannelSubscriber::Ring),
                     }
                 }
+            })
+            .await?;
+            let Some(subscriber) = declared else {
+                timed_out(&state);
+                undeclared.push(channel.to_string());
+                continue;
+            };
+            let subscriber = subscriber.map_err(declare_err)?;
+            declared_channels.push(channel.clone());
+            group.push((state, subscriber));
+            if group.len() >= args.subscribers_per_task as usize {
+                subscriber_tokens.push(spawn_subscriber_group(
+                    std::mem::take(&mut group),
+                    cancel_token.clone(),
+                ));
             }
         }
+    }
+    if !group.is_empty() {
+        subscriber_tokens.push(spawn_subscriber_group(group, cancel_token.clone()));
+    }
+    if undeclared.len() == channels.len() {
+        return Err(anyhow!(
+            "No channel could be declared within --declare-timeout-ms"
+        ));
+    } else if !undeclared.is_empty() {
+        diag!(
+            "Capturing without the channels that timed out: {}",
+            undeclared.join(", ")
+        );
+    }
+
+    // Setup the sink, it runs until every sender is gone and the queue is drained
+    let (pause_tx, pause_rx) = watch::channel(false);
+    #[cfg(unix)]
+    join_tokens.push(spawn_pause_toggle(pause_tx, cancel_token.clone())?);
+    #[cfg(not(unix))]
+    drop(pause_tx);
+
+    let sink_token = tokio::spawn({
+        // Setup writer
+        let window = payload_window(&args);
+        // The header has to fit the largest per-key snaplen too
+        let header_window = PayloadWindow {
+            head: window.head.map(|head| {
+                key_snaplens
+                    .iter()
+                    .map(|(_, snaplen)| *snaplen)
+                    .fold(head, usize::max)
+            }),
+            ..window
+        };
+        let writer = if args.split_by_priority {
+            Output::ByPriority(SplitWriter::new(
+                args.fifo.clone().unwrap_or_default(),
+                header_window,
+            ))
+        } else {
+            Output::Single(single_writer(&args, header_window)?)
+        };
+        let sink = Sink {
+            payload_grep,
+            tee,
+            audit,
+            text_log,
+            key_snaplens,
+            keepalive: keepalive.clone().map(|key| (key, heartbeat_seen.clone())),
+            channel_bases: args
+                .per_channel_timestamps
+                .then(|| ChannelBases::new(channel_names.clone())),
+            channel_kinds,
+            overloaded: overloaded.clone(),
+            ..Sink::new(&args, writer, stats.clone(), Clock::System)
+        };
+        sink.run(sink_rx, pause_rx)
     });
-    join_tokens.push(join_token);
 
-    // Wait for ctrl_c and gracefully quit the application
-    signal::ctrl_c().await?;
+    if let Some(key) = keepalive {
+        join_tokens.push(spawn_keepalive(
+            session.clone(),
+            key,
+            Duration::from_secs(args.keepalive_interval),
+            Duration::from_millis(args.keepalive_timeout_ms),
+            heartbeat_seen,
+            cancel_token.clone(),
+        ));
+    }
+
+    #[cfg(feature = "statsd")]
+    if let Some(socket) = statsd {
+        join_tokens.push(stats::statsd::spawn(
+            socket,
+            Duration::from_secs(args.statsd_interval),
+            stats.clone(),
+            cancel_token.clone(),
+        ));
+    }
+
+    // Wait for ctrl_c (or all single-shot channels) and gracefully quit the application
+    tokio::select! {
+        _ = interrupted.cancelled() => spawn_force_exit(force_exit_window),
+        _ = cancel_token.cancelled() => {}
+    }
+
+    // Stop the subscribers first so no new samples enter the queue
     cancel_token.cancel();
+    for token in subscriber_tokens {
+        token.await?;
+    }
+    if single_shot {
+        for state in &channel_states {
+            if state.fired() {
+                diag!("Channel {} produced a sample", state.channel);
+            } else {
+                diag!("Channel {} did not produce a sample", state.channel);
+            }
+        }
+    }
+    if args.dedup_overlap {
+        diag!(
+            "Dropped {} duplicate samples from overlapping channels",
+            duplicates.load(Ordering::Relaxed)
+        );
+    }
+    if let BackpressurePolicy::Drop = args.backpressure_policy {
+        diag!(
+            "Dropped {} samples while the writer queue was full",
+            overloaded.load(Ordering::Relaxed)
+        );
+    }
+
+    // Closing the last sender lets the sink drain the queue and stop
+    drop(sink_tx);
+    let files = sink_token.await?;
+
     for token in join_tokens {
         token.await?;
     }
 
+    if let Some(path) = &args.manifest {
+        manifest::write(
+            path,
+            &files,
+            CaptureInfo {
+                channels: &channel_names,
+                started,
+                stats: &stats,
+            },
+        )?;
+    }
+
     Ok(())
 }
 
-struct FIFOWriter {
-    inner: Arc<Mutex<FIFOWriterInner>>,
-    startup_time: Instant,
+/// What happens to samples that arrive while the writer is behind
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum BackpressurePolicy {
+    /// Keep them in an unbounded queue until the writer catches up
+    Queue,
+    /// Drop them when the bounded queue between subscribers and writer is full
+    Drop,
 }
 
-enum FIFOWriterInner {
-    StdOut(PcapWriter<Stdout>),
-    File(PcapWriter<File>),
+/// Why a sample did not make it into the writer queue
+enum QueueError {
+    Full,
+    Closed,
 }
 
-impl FIFOWriter {
-    pub fn new(fifo: Option<String>) -> anyhow::Result<Self> {
-        let header = PcapHeader {
-            version_major: 2,
-            version_minor: 4,
-            ts_correction: 0,
-            ts_accuracy: 0,
-            snaplen: u16::MAX as u32,
-            datalink: DataLink::RAW,
-            ts_resolution: pcap_file::TsResolution::MicroSecond,
-            endianness: Endianness::native(),
-        };
+type QueuedSample = (usize, Sample);
 
-        let inner = match fifo {
-            Some(fifo) => {
-                let file = File::options().create(true).append(true).open(&fifo)?;
-                let writer = PcapWriter::with_header(file, header)?;
-                FIFOWriterInner::File(writer)
+/// The writer queue for this backpressure policy, bounded to `size` samples when dropping
+fn sink_queue(policy: BackpressurePolicy, size: usize) -> (SinkSender, SinkReceiver) {
+    match policy {
+        BackpressurePolicy::Queue => {
+            let (sender, receiver) = mpsc::unbounded_channel();
+            (SinkSender::Queue(sender), SinkReceiver::Queue(receiver))
+        }
+        BackpressurePolicy::Drop => {
+            let (sender, receiver) = mpsc::channel(size);
+            (SinkSender::Drop(sender), SinkReceiver::Drop(receiver))
+        }
+    }
+}
+
+#[derive(Clone)]
+enum SinkSender {
+    Queue(mpsc::UnboundedSender<QueuedSample>),
+    Drop(mpsc::Sender<QueuedSample>),
+}
+
+impl SinkSender {
+    /// Never waits for the writer, a full bounded queue drops the sample
+    pub fn send(&self, sample: QueuedSample) -> Result<(), QueueError> {
+        match self {
+            SinkSender::Queue(sender) => sender.send(sample).map_err(|_| QueueError::Closed),
+            SinkSender::Drop(sender) => sender.try_send(sample).map_err(|err| match err {
+                mpsc::error::TrySendError::Full(_) => QueueError::Full,
+                mpsc::error::TrySendError::Closed(_) => QueueError::Closed,
+            }),
+        }
+    }
+}
+
+enum SinkReceiver {
+    Queue(mpsc::UnboundedReceiver<QueuedSample>),
+    Drop(mpsc::Receiver<QueuedSample>),
+}
+
+impl SinkReceiver {
+    pub async fn recv(&mut self) -> Option<QueuedSample> {
+        match self {
+            SinkReceiver::Queue(receiver) => receiver.recv().await,
+            SinkReceiver::Drop(receiver) => receiver.recv().await,
+        }
+    }
+
+    pub fn try_recv(&mut self) -> Option<QueuedSample> {
+        match self {
+            SinkReceiver::Queue(receiver) => receiver.try_recv().ok(),
+            SinkReceiver::Drop(receiver) => receiver.try_recv().ok(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        match self {
+            SinkReceiver::Queue(receiver) => receiver.is_empty(),
+            SinkReceiver::Drop(receiver) => receiver.is_empty(),
+        }
+    }
+}
+
+/// Per-channel bookkeeping shared by the channel task and the subscriber callback
+struct ChannelState {
+    index: usize,
+    channel: String,
+    overlapping: Vec<KeyExpr<'static>>,
+    duplicates: Arc<AtomicU64>,
+    /// Samples dropped because the writer queue was full
+    overloaded: Arc<AtomicU64>,
+    sink_tx: Mutex<Option<SinkSender>>,
+    stats: Arc<Stats>,
+    single_shot: bool,
+    pending_channels: Arc<AtomicUsize>,
+    cancel_token: CancellationToken,
+    fired: AtomicBool,
+}
+
+impl ChannelState {
+    /// Forward a sample to the sink, returns false once the channel is done
+    pub fn on_sample(&self, sample: Sample) -> bool {
+        if self
+            .overlapping
+            .iter()
+            .any(|earlier| earlier.includes(sample.key_expr()))
+        {
+            self.duplicates.fetch_add(1, Ordering::Relaxed);
+            return true;
+        }
+
+        let first = !self.fired.swap(true, Ordering::SeqCst);
+        if self.single_shot && !first {
+            return false;
+        }
+
+        // Poison errors are hard errors
+        if let Some(sink_tx) = &*self.sink_tx.lock().unwrap() {
+            self.stats.record_received(self.index);
+            match sink_tx.send((self.index, sample)) {
+                Ok(()) => {}
+                Err(QueueError::Full) => {
+                    self.overloaded.fetch_add(1, Ordering::Relaxed);
+                    self.stats.record_dropped();
+                }
+                Err(QueueError::Closed) => self.stats.record_dropped(),
             }
-            None => {
-                let stdout = std::io::stdout();
-                let writer = PcapWriter::with_header(stdout, header)?;
-                FIFOWriterInner::StdOut(writer)
+        }
+
+        if self.single_shot {
+            // Last channel to fire ends the capture
+            if self.pending_channels.fetch_sub(1, Ordering::SeqCst) == 1 {
+                self.cancel_token.cancel();
             }
-        };
+            return false;
+        }
+        true
+    }
 
-        Ok(Self {
-            inner: Arc::new(Mutex::new(inner)),
-            startup_time: Instant::now(),
-        })
+    /// Give up on a channel that could not be declared
+    pub fn abandon(&self) {
+        self.close();
+        if self.single_shot && self.pending_channels.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.cancel_token.cancel();
+        }
     }
 
-    pub async fn write_pcap(&mut self, packet: Sample) -> anyhow::Result<()> {
-        let inner = self.inner.clone();
-        let startup_time = self.startup_time;
+    /// Release the sink sender so the sink can finish once the queue is drained
+    pub fn close(&self) {
+        self.sink_tx.lock().unwrap().take();
+    }
 
-        let _ = tokio::task::spawn_blocking(move || {
-            // Poinson errors are hard errors
-            let mut inner = inner.lock().unwrap();
-            let payload = packet.payload().to_bytes();
-            let packet = PcapPacket::new(
-                Instant::now() - startup_time,
-                packet.payload().len() as u32,
-                payload.as_ref(),
-            );
+    pub fn fired(&self) -> bool {
+        self.fired.load(Ordering::SeqCst)
+    }
+}
+
+/// The declared channels a sample of `channel` may also arrive on, such samples are kept from those
+fn overlapping_channels(
+    declared: &[KeyExpr<'static>],
+    channel: &KeyExpr<'static>,
+) -> Vec<KeyExpr<'static>> {
+    declared
+        .iter()
+        .filter(|earlier| earlier.intersects(channel))
+        .cloned()
+        .collect()
+}
 
-            match &mut *inner {
-                FIFOWriterInner::StdOut(w) => w.write_packet(&packet),
-                FIFOWriterInner::File(w) => w.write_packet(&packet),
+/// Warn about channels that match the same keys, every such sample is delivered once per channel
+fn warn_overlapping(channels: &[KeyExpr<'static>]) {
+    for (index, channel) in channels.iter().enumerate() {
+        for other in &channels[index + 1..] {
+            if channel.includes(other) {
+                diag!("Channel {other} is already covered by {channel} and can be removed");
+            } else if other.includes(channel) {
+                diag!("Channel {channel} is already covered by {other} and can be removed");
+            } else if channel.intersects(other) {
+                diag!(
+                    "Channels {channel} and {other} overlap, samples matching both are captured twice"
+                );
             }
-        })
-        .await?;
+        }
+    }
+}
+
+/// Everything that happens to a sample between the queue and the writer
+struct Sink {
+    writer: Output,
+    payload_grep: Option<regex::bytes::Regex>,
+    filtered: usize,
+    /// Timestamp of the last written packet, only tracked with --strict-order
+    last_timestamp: Option<Duration>,
+    tee: Option<mpsc::UnboundedSender<Sample>>,
+    encodings: Option<EncodingSummary>,
+    unflushed: bool,
+    audit: Option<AuditLog>,
+    text_log: Option<TextLog>,
+    window: PayloadWindow,
+    key_snaplens: Vec<(KeyExpr<'static>, usize)>,
+    stats: Arc<Stats>,
+    skip_empty: bool,
+    skipped: usize,
+    change_tracker: Option<ChangeTracker>,
+    debouncer: Option<Debouncer>,
+    preview_bytes: Option<usize>,
+    keepalive: Option<(KeyExpr<'static>, Arc<AtomicU64>)>,
+    clock: Clock,
+    startup_time: Instant,
+    channel_bases: Option<ChannelBases>,
+    rate_tracker: Option<RateTracker>,
+    pause_policy: PausePolicy,
+    paused: bool,
+    paused_samples: VecDeque<(Sample, Duration)>,
+    paused_dropped: usize,
+    /// Samples received before this much time passed are discarded
+    warmup: Duration,
+    warmup_discarded: usize,
+    kinds: Kinds,
+    channel_kinds: Vec<(KeyExpr<'static>, Kinds)>,
+    excluded_kinds: usize,
+    /// Payloads left as is by --canonicalize-json because they are not JSON
+    canonicalize_json: Option<usize>,
+    top_keys: usize,
+    heartbeat_file: Option<String>,
+    heartbeat_interval: Duration,
+    /// Chunked stdout is flushed on a timer rather than whenever the queue runs empty
+    chunked: bool,
+    fair_queue: Option<FairQueue>,
+    /// Samples dropped because the writer queue was full
+    overloaded: Arc<AtomicU64>,
+}
+
+impl Sink {
+    /// The sink described by the flags, the stages that are set up before connecting are left off
+    pub fn new(args: &Cli, writer: Output, stats: Arc<Stats>, clock: Clock) -> Self {
+        Self {
+            writer,
+            payload_grep: None,
+            filtered: 0,
+            last_timestamp: args.strict_order.then_some(Duration::ZERO),
+            tee: None,
+            encodings: args.encoding_summary.then(EncodingSummary::default),
+            unflushed: false,
+            audit: None,
+            text_log: None,
+            window: payload_window(args),
+            key_snaplens: Vec::new(),
+            stats,
+            skip_empty: args.skip_empty,
+            skipped: 0,
+            change_tracker: args.suppress_unchanged.then(ChangeTracker::default),
+            debouncer: args
+                .min_interval
+                .map(|interval| Debouncer::new(Duration::from_millis(interval))),
+            preview_bytes: args.verbose.then_some(if args.metadata_only {
+                0
+            } else {
+                args.preview_bytes
+            }),
+            keepalive: None,
+            startup_time: clock.now(),
+            clock,
+            channel_bases: None,
+            rate_tracker: (args.top_keys > 0)
+                .then(|| RateTracker::new(Duration::from_secs(args.rate_window), MAX_TRACKED_KEYS)),
+            pause_policy: args.pause_policy,
+            paused: false,
+            paused_samples: VecDeque::new(),
+            paused_dropped: 0,
+            warmup: Duration::from_secs(args.warmup),
+            warmup_discarded: 0,
+            kinds: args.kinds,
+            channel_kinds: Vec::new(),
+            excluded_kinds: 0,
+            canonicalize_json: args.canonicalize_json.then_some(0),
+            top_keys: args.top_keys,
+            heartbeat_file: args.heartbeat_file.clone(),
+            heartbeat_interval: Duration::from_secs(args.heartbeat_file_interval),
+            chunked: args.stdout_chunk.is_some(),
+            fair_queue: args.fair_queue.then(|| {
+                // The bounded queue would only move the backlog into the fair queue, so it is
+                // bounded too
+                let capacity = match args.backpressure_policy {
+                    BackpressurePolicy::Queue => None,
+                    BackpressurePolicy::Drop => Some(args.queue_size as usize),
+                };
+                FairQueue::new(MAX_TRACKED_KEYS, capacity)
+            }),
+            overloaded: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Write the queued samples until every sender is gone and the queue is drained
+    ///
+    /// Returns the files written.
+    pub async fn run(
+        mut self,
+        mut sink_rx: SinkReceiver,
+        mut pause_rx: watch::Receiver<bool>,
+    ) -> Vec<PathBuf> {
+        let mut fair_queue = self.fair_queue.take();
+        let mut rate_report = tokio::time::interval(Duration::from_secs(1));
+        let mut heartbeat = tokio::time::interval(self.heartbeat_interval);
+        let mut chunk_flush = tokio::time::interval(STDOUT_CHUNK_FLUSH);
+
+        loop {
+            // Flush as soon as nothing is queued, bursts are written in batches
+            let queued = fair_queue.as_ref().is_some_and(|queue| !queue.is_empty());
+            if !self.chunked && !queued && sink_rx.is_empty() {
+                self.flush().await;
+            }
+            let debounce_deadline = self.debounce_deadline();
+            let debounce = debounce_deadline.unwrap_or_else(Instant::now);
+            tokio::select! {
+                sample = sink_rx.recv() => {
+                    match (sample, &mut fair_queue) {
+                        (Some((channel, sample)), None) => self.receive(channel, sample).await,
+                        (Some((channel, sample)), Some(queue)) => {
+                            // Take everything queued so the next round sees every key
+                            let mut queued = Some((channel, sample));
+                            while let Some((channel, sample)) = queued.take().or_else(|| sink_rx.try_recv()) {
+                                if !queue.push(channel, sample) {
+                                    self.overloaded.fetch_add(1, Ordering::Relaxed);
+                                    self.stats.record_dropped();
+                                }
+                            }
+                        }
+                        (None, _) => {
+                            // All subscribers are gone and the queue is drained
+                            while let Some((channel, sample)) =
+                                fair_queue.as_mut().and_then(FairQueue::pop)
+                            {
+                                self.receive(channel, sample).await;
+                            }
+                            break
+                        }
+                    }
+                }
+                _ = std::future::ready(()), if queued => {
+                    if let Some((channel, sample)) = fair_queue.as_mut().and_then(FairQueue::pop) {
+                        self.receive(channel, sample).await;
+                    }
+                }
+                _ = tokio::time::sleep_until(debounce.into()), if debounce_deadline.is_some() => {
+                    self.flush_debounced(Instant::now()).await;
+                }
+                Ok(()) = pause_rx.changed() => {
+                    let paused = *pause_rx.borrow_and_update();
+                    self.set_paused(paused).await;
+                }
+                _ = chunk_flush.tick(), if self.chunked => self.flush().await,
+                _ = rate_report.tick(), if self.rate_tracker.is_some() => {
+                    self.report_rates(self.top_keys);
+                }
+                _ = heartbeat.tick(), if self.heartbeat_file.is_some() => {
+                    // Ticking here stops as soon as a write blocks the loop
+                    if let Some(path) = &self.heartbeat_file {
+                        touch_heartbeat(path);
+                    }
+                }
+            }
+        }
+
+        self.finish().await
+    }
+
+    pub async fn receive(&mut self, channel: usize, sample: Sample) {
+        let now = self.clock.now();
+        if now.duration_since(self.startup_time) < self.warmup {
+            self.warmup_discarded += 1;
+            return;
+        }
+        if !self.kinds_for(&sample).includes(sample.kind()) {
+            self.excluded_kinds += 1;
+            return;
+        }
+        let timestamp = match &mut self.channel_bases {
+            Some(bases) => bases.elapsed(channel, now),
+            None => now.duration_since(self.startup_time),
+        };
+        if let Some(tracker) = &mut self.rate_tracker {
+            tracker.record(sample.key_expr().as_str());
+        }
+        if let Some(audit) = &mut self.audit {
+            audit.record(&sample);
+        }
+
+        if self.paused {
+            match self.pause_policy {
+                PausePolicy::Drop => self.paused_dropped += 1,
+                PausePolicy::Buffer => self.paused_samples.push_back((sample, timestamp)),
+            }
+            return;
+        }
+        self.forward(sample, timestamp).await;
+    }
+
+    async fn forward(&mut self, sample: Sample, timestamp: Duration) {
+        match &mut self.debouncer {
+            Some(debouncer) => debouncer.push(sample, timestamp, self.clock.now()),
+            None => self.write(sample, timestamp).await,
+        }
+    }
+
+    pub fn debounce_deadline(&self) -> Option<Instant> {
+        self.debouncer.as_ref()?.next_deadline()
+    }
+
+    /// Write the debounced samples whose window closed before `now`
+    pub async fn flush_debounced(&mut self, now: Instant) {
+        while let Some((sample, timestamp)) = self
+            .debouncer
+            .as_mut()
+            .and_then(|debouncer| debouncer.pop_due(now))
+        {
+            self.write(sample, timestamp).await;
+        }
+    }
+
+    async fn write(&mut self, sample: Sample, timestamp: Duration) {
+        // Only the capture is canonicalized, the tee republishes the payload as received
+        let canonical = self.canonicalize_json.as_mut().and_then(|passed_through| {
+            let canonical = canonical_json(sample.payload());
+            if canonical.is_none() {
+                *passed_through += 1;
+            }
+            canonical
+        });
+        let captured = match canonical {
+            Some(canonical) => {
+                let mut captured = sample.clone();
+                *captured.payload_mut() = canonical.into();
+                Cow::Owned(captured)
+            }
+            None => Cow::Borrowed(&sample),
+        };
+        if let Some(tracker) = &mut self.change_tracker
+            && tracker.is_unchanged(&captured)
+        {
+            return;
+        }
+
+        // Output new sample
+        let len = captured.payload().len();
+        if let Some(grep) = &self.payload_grep
+            && !grep.is_match(&sample.payload().to_bytes())
+        {
+            self.filtered += 1;
+            return;
+        }
+        let window = self.window_for(&captured);
+        if self.skip_empty && window.range(len).is_empty() {
+            self.skipped += 1;
+            return;
+        }
+        if let Some(preview_bytes) = self.preview_bytes {
+            print_preview(&sample, preview_bytes);
+        }
+        let heartbeat = self
+            .keepalive
+            .as_ref()
+            .filter(|(key, _)| sample.key_expr() == key)
+            .and_then(|_| sample.payload().try_to_string().ok()?.parse::<u64>().ok());
+        if let Some(text_log) = &mut self.text_log {
+            text_log.write(&captured, timestamp);
+        }
+        if let Some(tee) = &self.tee {
+            // The tee task outlives the sink, a send can only fail once it panicked
+            let _ = tee.send(sample.clone());
+        }
+        if let Some(last) = &mut self.last_timestamp {
+            assert!(
+                timestamp >= *last,
+                "--strict-order: packet on {} at {timestamp:?} is written after a packet at {last:?}",
+                sample.key_expr()
+            );
+            *last = timestamp;
+        }
+        let encoding = sample.encoding().to_string();
+        match self.writer.write_pcap(&captured, timestamp, window).await {
+            Ok(()) => {
+                self.unflushed = true;
+                self.stats.record_written(len);
+                if let Some(encodings) = &mut self.encodings {
+                    encodings.record(&encoding, len);
+                }
+                if let (Some((_, heartbeat_seen)), Some(heartbeat)) = (&self.keepalive, heartbeat) {
+                    heartbeat_seen.fetch_max(heartbeat, Ordering::SeqCst);
+                }
+            }
+            Err(err) => {
+                self.stats.record_dropped();
+                diag!("Error while writing to pcap with reason: {err}");
+            }
+        }
+    }
+
+    /// The global kinds, or those of the first matching `--channel-kinds`
+    fn kinds_for(&self, sample: &Sample) -> Kinds {
+        self.channel_kinds
+            .iter()
+            .find(|(key, _)| key.includes(sample.key_expr()))
+            .map_or(self.kinds, |(_, kinds)| *kinds)
+    }
+
+    /// The global window, with the snaplen of the first matching `--key-snaplen`
+    fn window_for(&self, sample: &Sample) -> PayloadWindow {
+        let snaplen = self
+            .key_snaplens
+            .iter()
+            .find(|(key, _)| key.includes(sample.key_expr()))
+            .map(|(_, snaplen)| *snaplen);
+        PayloadWindow {
+            head: snaplen.or(self.window.head),
+            ..self.window
+        }
+    }
+
+    /// Flush what the writer buffered since the last flush
+    pub async fn flush(&mut self) {
+        if !self.unflushed {
+            return;
+        }
+        self.unflushed = false;
+        if let Err(err) = self.writer.flush().await {
+            diag!("Error while flushing pcap with reason: {err}");
+        }
+    }
+
+    pub async fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+        if paused {
+            diag!("Capture paused");
+            return;
+        }
+
+        diag!(
+            "Capture resumed, writing {} buffered and dropped {} samples while paused",
+            self.paused_samples.len(),
+            self.paused_dropped
+        );
+        self.paused_dropped = 0;
+        self.flush_paused().await;
+    }
+
+    async fn flush_paused(&mut self) {
+        while let Some((sample, timestamp)) = self.paused_samples.pop_front() {
+            self.forward(sample, timestamp).await;
+        }
+    }
+
+    pub fn report_rates(&mut self, top_keys: usize) {
+        if let Some(tracker) = &mut self.rate_tracker {
+            print_rates(&tracker.top(top_keys));
+        }
+    }
+
+    /// Write what is still buffered and report the counters, returns the files written
+    pub async fn finish(mut self) -> Vec<PathBuf> {
+        self.flush_paused().await;
+        if let Some(debouncer) = &self.debouncer {
+            // Windows that are still open are closed early
+            let last = debouncer.deadlines.back().map(|(deadline, _)| *deadline);
+            if let Some(last) = last {
+                self.flush_debounced(last).await;
+            }
+        }
+        self.flush().await;
+
+        if let Some(debouncer) = &self.debouncer {
+            diag!(
+                "Coalesced {} samples within --min-interval",
+                debouncer.coalesced
+            );
+        }
+        if let Some(encodings) = &self.encodings {
+            encodings.print();
+        }
+        if let Some(tracker) = &self.change_tracker {
+            diag!("Suppressed {} unchanged samples", tracker.suppressed);
+        }
+        if self.skip_empty {
+            diag!("Skipped {} empty packets", self.skipped);
+        }
+        if self.payload_grep.is_some() {
+            diag!(
+                "Filtered {} samples not matching --payload-grep",
+                self.filtered
+            );
+        }
+        if self.paused_dropped > 0 {
+            diag!("Dropped {} samples while paused", self.paused_dropped);
+        }
+        if let Some(passed_through) = self.canonicalize_json {
+            diag!("Kept {passed_through} payloads that are not JSON as is");
+        }
+        if self.excluded_kinds > 0 {
+            diag!("Excluded {} samples by their kind", self.excluded_kinds);
+        }
+        if !self.warmup.is_zero() {
+            diag!(
+                "Discarded {} samples during --warmup",
+                self.warmup_discarded
+            );
+        }
+        // Dropping the writers closes the files, finishing compressed ones
+        self.writer.files()
+    }
+}
+
+/// Sample kinds captured on a key
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum Kinds {
+    /// PUT samples only
+    Put,
+    /// DELETE samples only
+    Delete,
+    /// Both PUT and DELETE samples
+    All,
+}
+
+impl Kinds {
+    pub fn includes(self, kind: SampleKind) -> bool {
+        match self {
+            Kinds::Put => kind == SampleKind::Put,
+            Kinds::Delete => kind == SampleKind::Delete,
+            Kinds::All => true,
+        }
+    }
+}
+
+/// What happens to samples received while the capture is paused
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum PausePolicy {
+    /// Drop them
+    Drop,
+    /// Keep them in memory and write them on resume
+    Buffer,
+}
+
+/// Toggle the pause state every time SIGUSR1 is received
+#[cfg(unix)]
+fn spawn_pause_toggle(
+    pause_tx: watch::Sender<bool>,
+    cancel_token: CancellationToken,
+) -> anyhow::Result<JoinHandle<()>> {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut usr1 = signal(SignalKind::user_defined1())
+        .map_err(|err| anyhow!("Could not listen for SIGUSR1 with reason: {err}"))?;
+    Ok(tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                Some(()) = usr1.recv() => pause_tx.send_modify(|paused| *paused = !*paused),
+                _ = cancel_token.cancelled() => break,
+            }
+        }
+    }))
+}
+
+/// What a zenoh subscriber does with samples that arrive while its buffer is full
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum SubscriberHandler {
+    /// Block until there is room, pushing back on the network
+    Fifo,
+    /// Drop the oldest buffered sample to make room
+    Ring,
+}
+
+/// Zenoh subscriber with either handler
+enum ChannelSubscriber {
+    Fifo(Subscriber<FifoChannelHandler<Sample>>),
+    Ring(Subscriber<RingChannelHandler<Sample>>),
+}
+
+impl ChannelSubscriber {
+    /// The samples delivered until the subscriber stops, dropping the stream undeclares it
+    fn into_stream(self) -> BoxStream<'static, Sample> {
+        match self {
+            ChannelSubscriber::Fifo(subscriber) => {
+                stream::unfold(subscriber, |subscriber| async move {
+                    Some((subscriber.recv_async().await.ok()?, subscriber))
+                })
+                .boxed()
+            }
+            ChannelSubscriber::Ring(subscriber) => {
+                stream::unfold(subscriber, |subscriber| async move {
+                    Some((subscriber.recv_async().await.ok()?, subscriber))
+                })
+                .boxed()
+            }
+        }
+    }
+}
+
+/// Service a group of subscribers from a single task until cancelled
+fn spawn_subscriber_group(
+    group: Vec<(Arc<ChannelState>, ChannelSubscriber)>,
+    cancel_token: CancellationToken,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut samples = SelectAll::new();
+        let mut states = Vec::with_capacity(group.len());
+        for (state, subscriber) in group {
+            states.push(state.clone());
+            // A trailing None marks a subscriber that stopped delivering samples
+            let stream = subscriber
+                .into_stream()
+                .map(Some)
+                .chain(stream::iter([None]))
+                .map(move |sample| match sample {
+                    Some(sample) => state.on_sample(sample),
+                    None => {
+                        diag!(
+                            "Error while listening on zenoh channel {}: subscriber closed",
+                            state.channel
+                        );
+                        false
+                    }
+                })
+                // The stream, and with it the subscriber, ends once the channel is done
+                .take_while(|more| std::future::ready(*more));
+            samples.push(stream);
+        }
+
+        loop {
+            tokio::select! {
+                next = samples.next() => {
+                    if next.is_none() {
+                        // Every subscriber in the group is gone
+                        break
+                    }
+                }
+                _ = cancel_token.cancelled() => {
+                    // Someone pressed ctrl_c, so quiting
+                    break
+                }
+            }
+        }
+        drop(samples);
+
+        for state in &states {
+            state.close();
+        }
+    })
+}
+
+/// Rewrite `path` with the current time, which also bumps its mtime
+fn touch_heartbeat(path: &str) {
+    let now = humantime::format_rfc3339_seconds(SystemTime::now());
+    if let Err(err) = std::fs::write(path, format!("{now}\n")) {
+        diag!("Could not write heartbeat file {path} with reason: {err}");
+    }
+}
+
+/// Publish numbered heartbeats on `key` and warn when they do not make it into the capture
+fn spawn_keepalive(
+    session: zenoh::Session,
+    key: KeyExpr<'static>,
+    interval: Duration,
+    timeout: Duration,
+    heartbeat_seen: Arc<AtomicU64>,
+    cancel_token: CancellationToken,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        for heartbeat in 1u64.. {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = cancel_token.cancelled() => break,
+            }
+
+            if let Err(err) = session.put(&key, heartbeat.to_string()).await {
+                diag!("Could not publish keepalive on {key} with reason: {err}");
+                continue;
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(timeout) => {}
+                _ = cancel_token.cancelled() => break,
+            }
+            if heartbeat_seen.load(Ordering::SeqCst) < heartbeat {
+                diag!(
+                    "Keepalive {heartbeat} on {key} did not appear in the capture within {}ms",
+                    timeout.as_millis()
+                );
+            }
+        }
+    })
+}
+
+/// Exit immediately when ctrl-c is pressed twice within `window` while shutting down
+fn spawn_force_exit(window: Duration) {
+    tokio::spawn(async move {
+        let mut last_press = Instant::now();
+        while signal::ctrl_c().await.is_ok() {
+            if last_press.elapsed() <= window {
+                diag!("Forced exit, buffered packets are discarded");
+                std::process::exit(130);
+            }
+            last_press = Instant::now();
+        }
+    });
+}
+
+fn print_rates(rates: &[(String, f64)]) {
+    if rates.is_empty() {
+        return;
+    }
+    let rates = rates
+        .iter()
+        .map(|(key, rate)| format!("{key} {rate:.1}/s"))
+        .collect::<Vec<_>>()
+        .join(", ");
+    diag!("Top keys: {rates}");
+}
+
+/// A column of the text log
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum TextColumn {
+    /// Seconds since the start of the capture, as in the pcap
+    Timestamp,
+    /// Key expression of the sample
+    Key,
+    /// Payload size in bytes, before any window is applied
+    Size,
+    /// Encoding of the payload
+    Encoding,
+    /// PUT or DELETE
+    Kind,
+    /// Priority of the sample
+    Priority,
+}
+
+/// Tab separated text log, one line per sample and flushed per line for `tail -f`
+struct TextLog {
+    file: LineWriter<File>,
+    columns: Vec<TextColumn>,
+}
+
+impl TextLog {
+    pub fn new(path: &str, columns: Vec<TextColumn>) -> anyhow::Result<Self> {
+        let file = File::create(path)
+            .map_err(|err| anyhow!("Could not open text log {path} with reason: {err}"))?;
+        Ok(Self {
+            file: LineWriter::new(file),
+            columns,
+        })
+    }
+
+    pub fn write(&mut self, sample: &Sample, timestamp: Duration) {
+        let line = self
+            .columns
+            .iter()
+            .map(|column| match column {
+                TextColumn::Timestamp => format!("{:.6}", timestamp.as_secs_f64()),
+                TextColumn::Key => sample.key_expr().to_string(),
+                TextColumn::Size => sample.payload().len().to_string(),
+                TextColumn::Encoding => sample.encoding().to_string(),
+                TextColumn::Kind => sample.kind().to_string(),
+                TextColumn::Priority => format!("{:?}", sample.priority()),
+            })
+            .collect::<Vec<_>>()
+            .join("\t");
+        if let Err(err) = writeln!(self.file, "{line}") {
+            diag!("Error while writing to text log with reason: {err}");
+        }
+    }
+}
+
+/// Print a diagnostic line on stderr, prefixed with the current time when --log-timestamps is set
+fn diagnostic(message: std::fmt::Arguments) {
+    let line = if LOG_TIMESTAMPS.load(Ordering::Relaxed) {
+        format!(
+            "{} {message}\n",
+            humantime::format_rfc3339_millis(SystemTime::now())
+        )
+    } else {
+        format!("{message}\n")
+    };
+    // A single write per line keeps lines whole when stderr is redirected
+    let _ = std::io::stderr().lock().write_all(line.as_bytes());
+}
+
+/// The payload re-serialized with sorted keys and no whitespace, none if it is not JSON
+fn canonical_json(payload: &ZBytes) -> Option<Vec<u8>> {
+    // Objects are BTreeMaps without serde_json's preserve_order, so keys come out sorted
+    let value: serde_json::Value = serde_json::from_slice(&payload.to_bytes()).ok()?;
+    serde_json::to_vec(&value).ok()
+}
+
+/// Dump the metadata and the first `preview_bytes` of a sample to stderr
+fn print_preview(sample: &Sample, preview_bytes: usize) {
+    let payload = sample.payload().to_bytes();
+    diag!(
+        "{} ({} bytes, {})",
+        sample.key_expr(),
+        payload.len(),
+        sample.encoding()
+    );
+
+    let preview = &payload[..payload.len().min(preview_bytes)];
+    for (row, chunk) in preview.chunks(16).enumerate() {
+        let hex = chunk
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let ascii = chunk
+            .iter()
+            .map(|&byte| {
+                if byte.is_ascii_graphic() || byte == b' ' {
+                    byte as char
+                } else {
+                    '.'
+                }
+            })
+            .collect::<String>();
+        diag!("  {:04x}  {hex:<47}  |{ascii}|", row * 16);
+    }
+}
+
+/// Source of the time the sink stamps and filters samples with
+///
+/// Tests drive a manual clock instead of sleeping, the capture always reads the system clock.
+#[derive(Clone, Debug)]
+enum Clock {
+    System,
+    #[cfg(test)]
+    Manual(Arc<Mutex<Instant>>),
+}
+
+impl Clock {
+    pub fn now(&self) -> Instant {
+        match self {
+            Clock::System => Instant::now(),
+            #[cfg(test)]
+            Clock::Manual(now) => *now.lock().unwrap(),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn manual() -> Self {
+        Clock::Manual(Arc::new(Mutex::new(Instant::now())))
+    }
+
+    #[cfg(test)]
+    pub fn advance(&self, by: Duration) {
+        if let Clock::Manual(now) = self {
+            *now.lock().unwrap() += by;
+        }
+    }
+}
+
+/// First-seen instants used as timestamp base for every channel
+struct ChannelBases {
+    channels: Vec<String>,
+    bases: Vec<Option<Instant>>,
+}
+
+impl ChannelBases {
+    pub fn new(channels: Vec<String>) -> Self {
+        let bases = vec![None; channels.len()];
+        Self { channels, bases }
+    }
+
+    /// Time since the first sample on `channel`, reporting the absolute base when it is set
+    pub fn elapsed(&mut self, channel: usize, now: Instant) -> Duration {
+        let base = self.bases[channel].get_or_insert_with(|| {
+            diag!(
+                "Channel {} timestamp base: {}",
+                self.channels[channel],
+                humantime::format_rfc3339_micros(SystemTime::now())
+            );
+            now
+        });
+        now.duration_since(*base)
+    }
+}
+
+/// Remembers the payload hash of the last sample seen on every key
+#[derive(Default)]
+struct ChangeTracker {
+    last_payloads: HashMap<String, u64>,
+    suppressed: usize,
+}
+
+impl ChangeTracker {
+    pub fn is_unchanged(&mut self, sample: &Sample) -> bool {
+        let mut hasher = DefaultHasher::new();
+        sample.payload().to_bytes().hash(&mut hasher);
+        let hash = hasher.finish();
+
+        let previous = self
+            .last_payloads
+            .insert(sample.key_expr().as_str().to_string(), hash);
+        let unchanged = previous == Some(hash);
+        if unchanged {
+            self.suppressed += 1;
+        }
+        unchanged
+    }
+}
+
+/// Samples and bytes written per encoding
+#[derive(Default)]
+struct EncodingSummary {
+    encodings: HashMap<String, (u64, u64)>,
+    other: (u64, u64),
+}
+
+impl EncodingSummary {
+    pub fn record(&mut self, encoding: &str, bytes: usize) {
+        let counts = if !self.encodings.contains_key(encoding)
+            && self.encodings.len() >= MAX_TRACKED_ENCODINGS
+        {
+            &mut self.other
+        } else {
+            self.encodings.entry(encoding.to_string()).or_default()
+        };
+        counts.0 += 1;
+        counts.1 += bytes as u64;
+    }
+
+    pub fn print(&self) {
+        let mut encodings = self.encodings.iter().collect::<Vec<_>>();
+        encodings.sort_by_key(|(_, (samples, _))| Reverse(*samples));
+        diag!("Samples per encoding:");
+        for (encoding, (samples, bytes)) in encodings {
+            diag!("  {encoding:<32} {samples:>10} samples {bytes:>14} bytes");
+        }
+        if self.other.0 > 0 {
+            let (samples, bytes) = self.other;
+            diag!(
+                "  {:<32} {samples:>10} samples {bytes:>14} bytes",
+                "<other>"
+            );
+        }
+    }
+}
+
+/// Compile a --payload-grep pattern, literals are matched byte for byte
+fn payload_grep(pattern: &str, regex: bool) -> anyhow::Result<regex::bytes::Regex> {
+    let pattern = if regex {
+        pattern.to_string()
+    } else {
+        // Every byte of the literal is escaped, which keeps raw bytes intact
+        let escaped = unescape_bytes(pattern)?
+            .iter()
+            .map(|byte| format!("\\x{byte:02x}"))
+            .collect::<String>();
+        format!("(?-u){escaped}")
+    };
+    regex::bytes::Regex::new(&pattern)
+        .map_err(|err| anyhow!("Payload grep {pattern} is invalid with reason: {err}"))
+}
+
+/// Turn `\xNN` into the raw byte and `\\` into a backslash
+fn unescape_bytes(pattern: &str) -> anyhow::Result<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(pattern.len());
+    let mut rest = pattern.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        rest = tail;
+        if byte != b'\\' {
+            bytes.push(byte);
+            continue;
+        }
+        match rest {
+            [b'\\', tail @ ..] => {
+                bytes.push(b'\\');
+                rest = tail;
+            }
+            [b'x', high, low, tail @ ..] => {
+                let hex = std::str::from_utf8(&[*high, *low])
+                    .ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                    .ok_or_else(|| anyhow!("Payload grep {pattern} has an invalid \\x escape"))?;
+                bytes.push(hex);
+                rest = tail;
+            }
+            _ => return Err(anyhow!("Payload grep {pattern} has an invalid escape")),
+        }
+    }
+    Ok(bytes)
+}
+
+/// Per-key queues serviced round-robin, so a flooding key cannot starve the others
+struct FairQueue {
+    queues: HashMap<String, VecDeque<(usize, Sample)>>,
+    /// Keys with queued samples, in the order they are serviced
+    order: VecDeque<String>,
+    max_keys: usize,
+    /// Bound on the samples queued over all keys
+    capacity: Option<usize>,
+    len: usize,
+}
+
+impl FairQueue {
+    pub fn new(max_keys: usize, capacity: Option<usize>) -> Self {
+        Self {
+            queues: HashMap::new(),
+            order: VecDeque::new(),
+            max_keys,
+            capacity,
+            len: 0,
+        }
+    }
+
+    /// Queue a sample, returns false when the queue is full and the sample was dropped
+    pub fn push(&mut self, channel: usize, sample: Sample) -> bool {
+        if self.capacity.is_some_and(|capacity| self.len >= capacity) {
+            return false;
+        }
+        self.len += 1;
+        let mut key = sample.key_expr().as_str();
+        // Keys beyond the bound share a single queue
+        if !self.queues.contains_key(key) && self.queues.len() >= self.max_keys {
+            key = "";
+        }
+        if !self.queues.contains_key(key) {
+            self.order.push_back(key.to_string());
+        }
+        self.queues
+            .entry(key.to_string())
+            .or_default()
+            .push_back((channel, sample));
+        true
+    }
+
+    pub fn pop(&mut self) -> Option<(usize, Sample)> {
+        let key = self.order.pop_front()?;
+        let queue = self.queues.get_mut(&key)?;
+        let next = queue.pop_front();
+        self.len -= 1;
+        if queue.is_empty() {
+            self.queues.remove(&key);
+        } else {
+            self.order.push_back(key);
+        }
+        next
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+}
+
+/// Coalesces the samples of every key to the latest one per fixed window
+struct Debouncer {
+    interval: Duration,
+    // The latest sample of every open window, stamped with the close of its window
+    pending: HashMap<String, (Sample, Duration)>,
+    // Every window has the same length, so the deadlines are ordered by insertion
+    deadlines: VecDeque<(Instant, String)>,
+    coalesced: usize,
+}
+
+impl Debouncer {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            pending: HashMap::new(),
+            deadlines: VecDeque::new(),
+            coalesced: 0,
+        }
+    }
+
+    /// Keep the sample until the window of its key closes, replacing older ones
+    ///
+    /// The sample is written with the timestamp at which its window closes, so the samples come
+    /// out in timestamp order whatever key they are on.
+    pub fn push(&mut self, sample: Sample, timestamp: Duration, now: Instant) {
+        let key = sample.key_expr().as_str();
+        if let Some((pending, _)) = self.pending.get_mut(key) {
+            *pending = sample;
+            self.coalesced += 1;
+            return;
+        }
+        self.deadlines
+            .push_back((now + self.interval, key.to_string()));
+        self.pending
+            .insert(key.to_string(), (sample, timestamp + self.interval));
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.deadlines.front().map(|(deadline, _)| *deadline)
+    }
+
+    /// The next sample whose window closed before `now`
+    pub fn pop_due(&mut self, now: Instant) -> Option<(Sample, Duration)> {
+        let (deadline, _) = self.deadlines.front()?;
+        if *deadline > now {
+            return None;
+        }
+        let (_, key) = self.deadlines.pop_front()?;
+        self.pending.remove(&key)
+    }
+}
+
+/// Codec of a compressed capture file
+#[cfg(feature = "zstd")]
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum Compression {
+    Zstd,
+}
+
+/// The writer used when not splitting the output
+fn single_writer(args: &Cli, window: PayloadWindow) -> anyhow::Result<FIFOWriter> {
+    #[cfg(feature = "zstd")]
+    if let (Some(Compression::Zstd), Some(path)) = (args.compress, &args.fifo) {
+        return FIFOWriter::zstd(path, window, args.compress_level);
+    }
+    FIFOWriter::new(args.fifo.clone(), window, args.stdout_chunk)
+}
+
+/// Destination of the sink: a single writer or one file per priority band
+enum Output {
+    Single(FIFOWriter),
+    ByPriority(SplitWriter),
+}
+
+impl Output {
+    pub async fn write_pcap(
+        &mut self,
+        packet: &Sample,
+        timestamp: Duration,
+        window: PayloadWindow,
+    ) -> anyhow::Result<()> {
+        match self {
+            Output::Single(writer) => writer.write_pcap(packet, timestamp, window).await,
+            Output::ByPriority(split) => {
+                let band = priority_band(packet.priority());
+                split
+                    .writer(band)?
+                    .write_pcap(packet, timestamp, window)
+                    .await
+            }
+        }
+    }
+
+    /// Every file written so far
+    pub fn files(&self) -> Vec<PathBuf> {
+        match self {
+            Output::Single(writer) => writer.path.iter().cloned().collect(),
+            Output::ByPriority(split) => {
+                let mut files = split
+                    .writers
+                    .keys()
+                    .map(|suffix| split_path(&split.path, suffix))
+                    .collect::<Vec<_>>();
+                files.sort();
+                files
+            }
+        }
+    }
+
+    pub async fn flush(&mut self) -> anyhow::Result<()> {
+        match self {
+            Output::Single(writer) => writer.flush().await,
+            Output::ByPriority(split) => {
+                for writer in split.writers.values_mut() {
+                    writer.flush().await?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+fn priority_band(priority: Priority) -> &'static str {
+    match priority {
+        Priority::RealTime => "realtime",
+        Priority::InteractiveHigh | Priority::InteractiveLow => "interactive",
+        Priority::DataHigh | Priority::Data | Priority::DataLow => "data",
+        Priority::Background => "background",
+    }
+}
+
+/// Lazily opened writers, one per file name suffix, placed next to a base path
+struct SplitWriter {
+    path: PathBuf,
+    window: PayloadWindow,
+    writers: HashMap<String, FIFOWriter>,
+}
+
+impl SplitWriter {
+    pub fn new(path: impl Into<PathBuf>, window: PayloadWindow) -> Self {
+        Self {
+            path: path.into(),
+            window,
+            writers: HashMap::new(),
+        }
+    }
+
+    pub fn writer(&mut self, suffix: &str) -> anyhow::Result<&mut FIFOWriter> {
+        if !self.writers.contains_key(suffix) {
+            let path = split_path(&self.path, suffix);
+            let writer =
+                FIFOWriter::new(Some(path.to_string_lossy().into_owned()), self.window, None)?;
+            self.writers.insert(suffix.to_string(), writer);
+        }
+        Ok(self.writers.get_mut(suffix).unwrap())
+    }
+}
+
+/// `capture.pcap` with suffix `data` becomes `capture.data.pcap`
+fn split_path(path: &Path, suffix: &str) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(extension) => format!("{stem}.{suffix}.{}", extension.to_string_lossy()),
+        None => format!("{stem}.{suffix}"),
+    };
+    path.with_file_name(name)
+}
+
+/// Part of every payload that ends up in the capture
+#[derive(Clone, Copy, Debug, Default)]
+struct PayloadWindow {
+    offset: usize,
+    head: Option<usize>,
+    tail: Option<usize>,
+}
+
+impl PayloadWindow {
+    /// The offset is skipped first, the head or tail window applies to what remains
+    pub fn range(&self, len: usize) -> Range<usize> {
+        let start = self.offset.min(len);
+        match (self.head, self.tail) {
+            (Some(head), _) => start..len.min(start.saturating_add(head)),
+            (None, Some(tail)) => len.saturating_sub(tail).max(start)..len,
+            (None, None) => start..len,
+        }
+    }
+
+    /// Largest number of bytes a packet can hold
+    pub fn max_len(&self) -> Option<usize> {
+        self.head.or(self.tail)
+    }
+}
+
+/// Handle on a thread that owns the output and writes the packets it is sent in order
+struct FIFOWriter {
+    /// Dropped first to stop the thread
+    requests: Option<std::sync::mpsc::Sender<WriteRequest>>,
+    thread: Option<std::thread::JoinHandle<()>>,
+    /// File written to, none for stdout
+    path: Option<PathBuf>,
+}
+
+enum WriteRequest {
+    Write {
+        payload: ZBytes,
+        timestamp: Duration,
+        window: PayloadWindow,
+        done: oneshot::Sender<anyhow::Result<()>>,
+    },
+    Flush {
+        done: oneshot::Sender<std::io::Result<()>>,
+    },
+}
+
+/// Buffered output, flushed by the sink whenever its queue runs empty
+enum FIFOWriterInner {
+    StdOut(BufWriter<Stdout>),
+    File(BufWriter<File>),
+    /// The zstd frame is finished when the writer is dropped
+    #[cfg(feature = "zstd")]
+    Zstd(BufWriter<zstd::stream::AutoFinishEncoder<'static, File>>),
+}
+
+impl FIFOWriterInner {
+    fn out(&mut self) -> &mut dyn Write {
+        match self {
+            FIFOWriterInner::StdOut(w) => w,
+            FIFOWriterInner::File(w) => w,
+            #[cfg(feature = "zstd")]
+            FIFOWriterInner::Zstd(w) => w,
+        }
+    }
+}
+
+impl Write for FIFOWriterInner {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.out().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.out().flush()
+    }
+}
+
+impl FIFOWriter {
+    /// The snaplen is never below 65535, so it is not 0 with --metadata-only or --snaplen 0
+    fn header(window: PayloadWindow) -> PcapHeader {
+        PcapHeader {
+            version_major: 2,
+            version_minor: 4,
+            ts_correction: 0,
+            ts_accuracy: 0,
+            snaplen: window.max_len().map_or(u16::MAX as u32, |len| {
+                len.clamp(u16::MAX as usize, u32::MAX as usize) as u32
+            }),
+            datalink: DataLink::RAW,
+            ts_resolution: pcap_file::TsResolution::MicroSecond,
+            endianness: Endianness::native(),
+        }
+    }
+
+    /// Output is written in `chunk` sized pieces, or the `BufWriter` default without one
+    pub fn new(
+        fifo: Option<String>,
+        window: PayloadWindow,
+        chunk: Option<usize>,
+    ) -> anyhow::Result<Self> {
+        let header = Self::header(window);
+        let capacity = chunk.unwrap_or(DEFAULT_BUF_SIZE);
+        let path = fifo.as_ref().map(PathBuf::from);
+
+        let created = path.as_ref().is_some_and(|path| !path.exists());
+        let target = fifo.as_deref().unwrap_or("stdout");
+
+        let inner = match &fifo {
+            Some(fifo) => {
+                let file = File::options()
+                    .create(true)
+                    .append(true)
+                    .open(fifo)
+                    .map_err(|err| anyhow!("Could not open {fifo} with reason: {err}"))?;
+                FIFOWriterInner::File(BufWriter::with_capacity(capacity, file))
+            }
+            None => FIFOWriterInner::StdOut(BufWriter::with_capacity(capacity, std::io::stdout())),
+        };
+        let created = path.as_deref().filter(|_| created);
+        let inner = Self::with_header(inner, header, target, created)?;
+
+        Ok(Self::spawn(inner, header, path))
+    }
+
+    /// Write a zstd compressed capture to a regular file
+    #[cfg(feature = "zstd")]
+    pub fn zstd(path: &str, window: PayloadWindow, level: i32) -> anyhow::Result<Self> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::FileTypeExt;
+
+            if std::fs::metadata(path).is_ok_and(|metadata| metadata.file_type().is_fifo()) {
+                return Err(anyhow!(
+                    "Could not compress {path} with reason: live fifo targets cannot be compressed"
+                ));
+            }
+        }
+        let created = !Path::new(path).exists();
+        let file = File::options()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|err| anyhow!("Could not open {path} with reason: {err}"))?;
+        let encoder = zstd::Encoder::new(file, level)
+            .map_err(|err| anyhow!("Could not start zstd compression with reason: {err}"))?;
+        let header = Self::header(window);
+        let inner = FIFOWriterInner::Zstd(BufWriter::new(encoder.auto_finish()));
+        let inner = Self::with_header(inner, header, path, created.then_some(Path::new(path)))?;
+
+        Ok(Self::spawn(inner, header, Some(path.into())))
+    }
+
+    fn spawn(inner: FIFOWriterInner, header: PcapHeader, path: Option<PathBuf>) -> Self {
+        let (requests, receiver) = std::sync::mpsc::channel();
+        let thread = std::thread::spawn(move || Self::run(inner, header, receiver));
+        Self {
+            requests: Some(requests),
+            thread: Some(thread),
+            path,
+        }
+    }
+
+    /// Serve requests until the handle is dropped, the output is closed when this returns
+    fn run(
+        mut inner: FIFOWriterInner,
+        header: PcapHeader,
+        requests: std::sync::mpsc::Receiver<WriteRequest>,
+    ) {
+        // Fragmented payloads are gathered here, contiguous ones are written in place
+        let mut buffer = Vec::new();
+        for request in requests {
+            match request {
+                WriteRequest::Write {
+                    payload,
+                    timestamp,
+                    window,
+                    done,
+                } => {
+                    let mut slices = payload.slices();
+                    let payload = match (slices.next(), slices.next()) {
+                        (Some(slice), None) => slice,
+                        (None, _) => &[],
+                        _ => {
+                            buffer.clear();
+                            payload
+                                .slices()
+                                .for_each(|slice| buffer.extend_from_slice(slice));
+                            buffer.as_slice()
+                        }
+                    };
+                    // The original length keeps the bytes left out of the window
+                    let packet = PcapPacket::new(
+                        timestamp,
+                        payload.len() as u32,
+                        &payload[window.range(payload.len())],
+                    );
+                    let result = packet.write_to::<_, NativeEndian>(
+                        &mut inner.out(),
+                        header.ts_resolution,
+                        header.snaplen,
+                    );
+                    let _ = done.send(result.map(|_| ()).map_err(Into::into));
+                }
+                WriteRequest::Flush { done } => {
+                    let _ = done.send(inner.out().flush());
+                }
+            }
+        }
+    }
+
+    fn send(&self, request: WriteRequest) -> anyhow::Result<()> {
+        self.requests
+            .as_ref()
+            .and_then(|requests| requests.send(request).ok())
+            .ok_or_else(|| {
+                anyhow!("Could not write the capture with reason: the writer thread stopped")
+            })
+    }
+
+    /// The header is flushed right away so readers can start parsing the capture
+    ///
+    /// When that fails, the file at `created`, which this run created and holds no capture, is
+    /// removed once `out` is closed. Existing fifos and files are left alone.
+    fn with_header<W: Write>(
+        mut out: W,
+        header: PcapHeader,
+        target: &str,
+        created: Option<&Path>,
+    ) -> anyhow::Result<W> {
+        let written = header
+            .write_to(&mut out)
+            .map_err(anyhow::Error::from)
+            .and_then(|_| Ok(out.flush()?));
+        if let Err(err) = written {
+            drop(out);
+            if let Some(path) = created {
+                let _ = std::fs::remove_file(path);
+            }
+            return Err(anyhow!(
+                "Could not write the pcap header to {target} with reason: {err:#}"
+            ));
+        }
+        Ok(out)
+    }
+
+    /// Write the part of the payload inside `window`, the header window only sets the snaplen
+    pub async fn write_pcap(
+        &mut self,
+        packet: &Sample,
+        timestamp: Duration,
+        window: PayloadWindow,
+    ) -> anyhow::Result<()> {
+        let (done, result) = oneshot::channel();
+        self.send(WriteRequest::Write {
+            payload: packet.payload().clone(),
+            timestamp,
+            window,
+            done,
+        })?;
+        result.await?
+    }
+
+    pub async fn flush(&mut self) -> anyhow::Result<()> {
+        let (done, result) = oneshot::channel();
+        self.send(WriteRequest::Flush { done })?;
+        Ok(result.await??)
+    }
+}
+
+impl Drop for FIFOWriter {
+    /// Wait for the thread so the output is closed, and compressed files finished, once dropped
+    fn drop(&mut self) {
+        self.requests.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use zenoh::sample::SampleBuilder;
+
+    use super::*;
+
+    fn window(offset: usize, head: Option<usize>, tail: Option<usize>) -> PayloadWindow {
+        PayloadWindow { offset, head, tail }
+    }
+
+    #[test]
+    fn offset_past_the_payload_is_empty() {
+        assert_eq!(window(10, None, None).range(4), 4..4);
+        assert_eq!(window(10, Some(2), None).range(4), 4..4);
+        assert_eq!(window(10, None, Some(2)).range(4), 4..4);
+    }
+
+    #[test]
+    fn head_applies_after_the_offset() {
+        assert_eq!(window(2, Some(3), None).range(10), 2..5);
+        assert_eq!(window(2, Some(30), None).range(10), 2..10);
+        assert_eq!(window(0, Some(0), None).range(10), 0..0);
+    }
+
+    #[test]
+    fn tail_never_reaches_into_the_offset() {
+        assert_eq!(window(2, None, Some(3)).range(10), 7..10);
+        assert_eq!(window(2, None, Some(30)).range(10), 2..10);
+        assert_eq!(window(0, None, Some(0)).range(10), 10..10);
+    }
+
+    /// An empty directory under the system temp dir, unique per test
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("zenoh-dump-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn sample(on: &str, payload: &[u8]) -> Sample {
+        SampleBuilder::put(key(on), payload.to_vec()).into()
+    }
+
+    fn u32_at(bytes: &[u8], at: usize) -> u32 {
+        u32::from_ne_bytes(bytes[at..at + 4].try_into().unwrap())
+    }
+
+    #[tokio::test]
+    async fn metadata_only_writes_no_payload_bytes() {
+        let path = temp_dir("metadata-only").join("capture.pcap");
+        let window = window(0, Some(0), None);
+        let mut writer =
+            FIFOWriter::new(Some(path.to_string_lossy().into_owned()), window, None).unwrap();
+        writer
+            .write_pcap(&sample("demo/a", b"secret"), Duration::ZERO, window)
+            .await
+            .unwrap();
+        drop(writer);
+
+        let capture = std::fs::read(&path).unwrap();
+        // Global header, then one 16 byte record header and nothing else
+        assert_eq!(capture.len(), 24 + 16);
+        assert_eq!(u32_at(&capture, 16), u16::MAX as u32);
+        assert_eq!(u32_at(&capture, 24 + 8), 0);
+        assert_eq!(u32_at(&capture, 24 + 12), 6);
+    }
+
+    #[tokio::test]
+    async fn every_band_gets_its_own_capture() {
+        let dir = temp_dir("split");
+        let window = PayloadWindow::default();
+        let mut split = SplitWriter::new(dir.join("capture.pcap"), window);
+        for (band, payload) in [("data", b"1"), ("realtime", b"2"), ("data", b"3")] {
+            let writer = split.writer(band).unwrap();
+            writer
+                .write_pcap(&sample("demo/a", payload), Duration::ZERO, window)
+                .await
+                .unwrap();
+        }
+        drop(split);
+
+        let data = std::fs::read(dir.join("capture.data.pcap")).unwrap();
+        let realtime = std::fs::read(dir.join("capture.realtime.pcap")).unwrap();
+        // A single header per file, then the records of that band only
+        assert_eq!(data.len(), 24 + 2 * (16 + 1));
+        assert_eq!((data[24 + 16], data[24 + 2 * 16 + 1]), (b'1', b'3'));
+        assert_eq!(realtime.len(), 24 + 16 + 1);
+        assert_eq!(realtime[24 + 16], b'2');
+    }
+
+    #[cfg(feature = "zstd")]
+    #[tokio::test]
+    async fn compressed_capture_decodes_to_the_pcap() {
+        let path = temp_dir("zstd").join("capture.pcap.zst");
+        let window = PayloadWindow::default();
+        let mut writer = FIFOWriter::zstd(path.to_str().unwrap(), window, 3).unwrap();
+        writer
+            .write_pcap(
+                &sample("demo/a", b"hello"),
+                Duration::from_millis(1500),
+                window,
+            )
+            .await
+            .unwrap();
+        // The frame is only finished once the writer thread is gone
+        drop(writer);
+
+        let capture = zstd::decode_all(File::open(&path).unwrap()).unwrap();
+        assert_eq!(u32_at(&capture, 0), 0xa1b2c3d4);
+        assert_eq!(capture.len(), 24 + 16 + 5);
+        assert_eq!(u32_at(&capture, 24), 1);
+        assert_eq!(u32_at(&capture, 24 + 4), 500_000);
+        assert_eq!(u32_at(&capture, 24 + 8), 5);
+        assert_eq!(u32_at(&capture, 24 + 12), 5);
+        assert_eq!(&capture[24 + 16..], b"hello");
+    }
+
+    /// Output that fails every write, like a disk that filled up or a closed pipe
+    struct FailingWriter;
+
+    impl Write for FailingWriter {
+        fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+            Err(std::io::Error::other("device is gone"))
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn failed_header_removes_only_the_file_it_created() {
+        let dir = temp_dir("failed-header");
+        let header = FIFOWriter::header(PayloadWindow::default());
+        let created = dir.join("created.pcap");
+        std::fs::write(&created, b"").unwrap();
+        let existing = dir.join("existing.pcap");
+        std::fs::write(&existing, b"kept").unwrap();
+
+        let target = created.to_str().unwrap();
+        let err = FIFOWriter::with_header(FailingWriter, header, target, Some(&created))
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains(target), "{err}");
+        assert!(err.to_string().contains("device is gone"), "{err}");
+        assert!(!created.exists());
+
+        let target = existing.to_str().unwrap();
+        assert!(FIFOWriter::with_header(FailingWriter, header, target, None).is_err());
+        assert_eq!(std::fs::read(&existing).unwrap(), b"kept");
+
+        let written = FIFOWriter::with_header(Vec::new(), header, "stdout", None).unwrap();
+        assert_eq!(written.len(), 24);
+    }
+
+    #[test]
+    fn unwritable_target_is_named_in_the_error() {
+        let path = temp_dir("unwritable").join("missing").join("capture.pcap");
+        let path = path.to_string_lossy().into_owned();
+        let err = FIFOWriter::new(Some(path.clone()), PayloadWindow::default(), None)
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains(&path), "{err}");
+    }
+
+    #[tokio::test]
+    async fn every_queued_sample_is_written_once_the_senders_are_gone() {
+        for flags in [&[][..], &["--fair-queue"]] {
+            let path = temp_dir("drain").join("capture.pcap");
+            let sink = sink(&path, flags, Clock::System);
+            let (sink_tx, sink_rx) = sink_queue(BackpressurePolicy::Queue, 16);
+            let (_pause_tx, pause_rx) = watch::channel(false);
+            for index in 0..100u8 {
+                let on = if index % 3 == 0 { "demo/a" } else { "demo/b" };
+                assert!(sink_tx.send((0, sample(on, &[index]))).is_ok());
+            }
+            drop(sink_tx);
+
+            let files = sink.run(sink_rx, pause_rx).await;
+            assert_eq!(files, std::slice::from_ref(&path));
+            let capture = std::fs::read(&path).unwrap();
+            assert_eq!(capture.len(), 24 + 100 * (16 + 1), "{flags:?}");
+        }
+    }
+
+    /// A sink writing to `path`, configured by `flags`
+    fn sink(path: &Path, flags: &[&str], clock: Clock) -> Sink {
+        let args = Cli::try_parse_from(["zenoh-dump"].iter().chain(flags)).unwrap();
+        let path = path.to_string_lossy().into_owned();
+        let writer = FIFOWriter::new(Some(path), payload_window(&args), None).unwrap();
+        Sink::new(
+            &args,
+            Output::Single(writer),
+            Arc::new(Stats::new([])),
+            clock,
+        )
+    }
+
+    #[tokio::test]
+    async fn warmup_ends_exactly_after_its_duration() {
+        let path = temp_dir("warmup").join("capture.pcap");
+        let clock = Clock::manual();
+        let mut sink = sink(&path, &["--warmup", "5"], clock.clone());
+
+        clock.advance(Duration::from_millis(4999));
+        sink.receive(0, sample("demo/a", b"early")).await;
+        clock.advance(Duration::from_millis(1));
+        sink.receive(0, sample("demo/a", b"live")).await;
+        assert_eq!(sink.warmup_discarded, 1);
+        sink.finish().await;
+
+        let capture = std::fs::read(&path).unwrap();
+        assert_eq!(capture.len(), 24 + 16 + 4);
+        // Stamped at the end of the warmup, on the capture clock
+        assert_eq!(u32_at(&capture, 24), 5);
+        assert_eq!(u32_at(&capture, 24 + 4), 0);
+        assert_eq!(&capture[24 + 16..], b"live");
+    }
+
+    #[test]
+    fn only_repeated_payloads_are_suppressed() {
+        let mut tracker = ChangeTracker::default();
+        let unchanged = [b"1", b"1", b"2", b"2", b"1", b"2"]
+            .map(|payload| tracker.is_unchanged(&sample("demo/a", payload)));
+        assert_eq!(unchanged, [false, true, false, true, false, false]);
+        assert_eq!(tracker.suppressed, 2);
+    }
+
+    #[test]
+    fn repeats_are_tracked_per_key() {
+        let mut tracker = ChangeTracker::default();
+        assert!(!tracker.is_unchanged(&sample("demo/a", b"on")));
+        assert!(!tracker.is_unchanged(&sample("demo/b", b"on")));
+        assert!(tracker.is_unchanged(&sample("demo/a", b"on")));
+        assert!(!tracker.is_unchanged(&sample("demo/b", b"off")));
+        assert!(tracker.is_unchanged(&sample("demo/a", b"on")));
+    }
+
+    #[test]
+    fn channel_kinds_take_the_first_matching_rule() {
+        let path = temp_dir("channel-kinds").join("capture.pcap");
+        let mut sink = sink(&path, &["--kinds", "put"], Clock::System);
+        sink.channel_kinds = [
+            "actuators/**=delete",
+            "actuators/valve=all",
+            "sensors/**=all",
+        ]
+        .iter()
+        .map(|spec| parse_channel_kinds(spec, false).unwrap())
+        .collect();
+        let kinds = |on| {
+            let kinds = sink.kinds_for(&sample(on, b""));
+            (
+                kinds.includes(SampleKind::Put),
+                kinds.includes(SampleKind::Delete),
+            )
+        };
+
+        // actuators/valve is already matched by actuators/**
+        assert_eq!(kinds("actuators/valve"), (false, true));
+        assert_eq!(kinds("sensors/temp"), (true, true));
+        // Falls back to --kinds
+        assert_eq!(kinds("control/mode"), (true, false));
+    }
+
+    #[test]
+    fn channel_kinds_need_a_key_and_valid_kinds() {
+        assert!(parse_channel_kinds("sensors/**", false).is_err());
+        assert!(parse_channel_kinds("sensors/**=patch", false).is_err());
+        assert!(parse_channel_kinds("sensors/*/a=PUT", false).is_ok());
+    }
+
+    fn key(key: &str) -> KeyExpr<'static> {
+        KeyExpr::try_from(key.to_string()).unwrap()
+    }
+
+    #[test]
+    fn overlap_is_only_deduplicated_against_declared_channels() {
+        let channel = key("foo/bar");
+        assert_eq!(
+            overlapping_channels(&[key("foo/**"), key("baz/**")], &channel),
+            [key("foo/**")]
+        );
+        // foo/** timed out and was skipped, nothing else captures foo/bar
+        assert!(overlapping_channels(&[key("baz/**")], &channel).is_empty());
+    }
+
+    #[test]
+    fn history_rejects_the_capture_only_flags() {
+        let history = ["zenoh-dump", "--capture", "--history"];
+        assert!(Cli::try_parse_from(history).is_ok());
+        assert!(Cli::try_parse_from(history.iter().chain(&["--snaplen", "8"])).is_ok());
+        for flags in [
+            &["--kinds", "put"][..],
+            &["--tee", "tcp/localhost:7447"],
+            &["--min-interval", "10"],
+        ] {
+            assert!(Cli::try_parse_from(history.iter().chain(flags)).is_err());
+        }
+    }
+
+    #[test]
+    fn reordered_json_is_canonicalized_alike() {
+        let canonical = canonical_json(&ZBytes::from(
+            r#"{"b": 1, "a": [true, {"d": null, "c": "x"}]}"#,
+        ));
+        assert_eq!(
+            canonical,
+            canonical_json(&ZBytes::from(r#"{"a":[true,{"c":"x","d":null}],"b":1}"#))
+        );
+        assert_eq!(
+            canonical.unwrap(),
+            br#"{"a":[true,{"c":"x","d":null}],"b":1}"#
+        );
+        assert_eq!(canonical_json(&ZBytes::from("{\"a\": 1,}")), None);
+        assert_eq!(canonical_json(&ZBytes::from(vec![0xff, 0xfe])), None);
+    }
+
+    /// The state of a channel declared after `declared`, with --dedup-overlap
+    fn dedup_state(declared: &[&str], channel: &str, sink_tx: SinkSender) -> ChannelState {
+        let declared = declared
+            .iter()
+            .map(|channel| key(channel))
+            .collect::<Vec<_>>();
+        ChannelState {
+            index: 0,
+            channel: channel.to_string(),
+            overlapping: overlapping_channels(&declared, &key(channel)),
+            duplicates: Arc::new(AtomicU64::new(0)),
+            overloaded: Arc::new(AtomicU64::new(0)),
+            sink_tx: Mutex::new(Some(sink_tx)),
+            stats: Arc::new(Stats::new([channel.to_string()])),
+            single_shot: false,
+            pending_channels: Arc::new(AtomicUsize::new(1)),
+            cancel_token: CancellationToken::new(),
+            fired: AtomicBool::new(false),
+        }
+    }
+
+    fn received(sink_rx: &mut SinkReceiver) -> Vec<String> {
+        std::iter::from_fn(|| sink_rx.try_recv())
+            .map(|(_, sample)| sample.key_expr().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn covered_channel_drops_the_duplicates() {
+        let (sink_tx, mut sink_rx) = sink_queue(BackpressurePolicy::Queue, 16);
+        let state = dedup_state(&["foo/**"], "foo/bar", sink_tx);
+        assert!(state.on_sample(sample("foo/bar", b"")));
+        assert!(state.on_sample(sample("foo/bar", b"")));
+        assert_eq!(state.duplicates.load(Ordering::Relaxed), 2);
+        assert!(received(&mut sink_rx).is_empty());
+
+        // The covering channel is declared later, it owns nothing of this one
+        let (sink_tx, mut sink_rx) = sink_queue(BackpressurePolicy::Queue, 16);
+        let state = dedup_state(&["foo/bar"], "foo/**", sink_tx);
+        assert!(state.on_sample(sample("foo/bar", b"")));
+        assert!(state.on_sample(sample("foo/baz", b"")));
+        assert_eq!(state.duplicates.load(Ordering::Relaxed), 1);
+        assert_eq!(received(&mut sink_rx), ["foo/baz"]);
+    }
+
+    #[test]
+    fn partial_overlap_only_drops_the_shared_keys() {
+        let (sink_tx, mut sink_rx) = sink_queue(BackpressurePolicy::Queue, 16);
+        let state = dedup_state(&["foo/*/x", "bar/**"], "foo/bar/*", sink_tx);
+        assert_eq!(state.overlapping, [key("foo/*/x")]);
+        assert!(state.on_sample(sample("foo/bar/x", b"")));
+        assert!(state.on_sample(sample("foo/bar/y", b"")));
+        assert_eq!(state.duplicates.load(Ordering::Relaxed), 1);
+        assert_eq!(received(&mut sink_rx), ["foo/bar/y"]);
+    }
+
+    #[test]
+    fn unescapes_hex_and_backslashes() {
+        assert_eq!(unescape_bytes(r"a\x00\xfFb").unwrap(), b"a\x00\xffb");
+        assert_eq!(unescape_bytes(r"a\\b").unwrap(), b"a\\b");
+        assert!(unescape_bytes(r"a\x4").is_err());
+        assert!(unescape_bytes(r"a\xzz").is_err());
+        assert!(unescape_bytes(r"a\n").is_err());
+        assert!(unescape_bytes("a\\").is_err());
+    }
+
+    #[test]
+    fn literal_grep_matches_raw_bytes() {
+        let grep = payload_grep(r"id\xff\x00.", false).unwrap();
+        assert!(grep.is_match(b"xx id\xff\x00. yy"));
+        assert!(!grep.is_match(b"xx id\xff\x00? yy"));
+        assert!(!grep.is_match(b"xx id\xc3\xbf\x00. yy"));
+    }
+
+    #[test]
+    fn regex_grep_matches_non_utf8_payloads() {
+        let grep = payload_grep(r"(?-u)\xff+[0-9]", true).unwrap();
+        assert!(grep.is_match(b"\xfe\xff\xff7"));
+        assert!(!grep.is_match(b"\xfe7"));
+        assert!(payload_grep("(", true).is_err());
+    }
+
+    fn channels_of(flags: &[&str]) -> anyhow::Result<Vec<String>> {
+        let args = Cli::try_parse_from(["zenoh-dump"].iter().chain(flags)).unwrap();
+        Ok(channels(&args)?
+            .iter()
+            .map(|channel| channel.to_string())
+            .collect())
+    }
+
+    #[test]
+    fn blank_channels_are_rejected() {
+        assert!(channels_of(&["--channels", ""]).is_err());
+        assert!(channels_of(&["--channels", " "]).is_err());
+        assert!(channels_of(&["--channels", "", "--channels", "\t"]).is_err());
+    }
 
-        Ok(())
+    #[test]
+    fn blank_channels_are_skipped_among_valid_ones() {
+        let flags = [
+            "--channels",
+            "",
+            "--channels",
+            " demo/a ",
+            "--channels",
+            " ",
+        ];
+        assert_eq!(channels_of(&flags).unwrap(), ["demo/a"]);
     }
 }
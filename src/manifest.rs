@@ -0,0 +1,126 @@
+use std::{
+    fs::File,
+    path::{Path, PathBuf},
+    sync::atomic::Ordering,
+    time::SystemTime,
+};
+
+use anyhow::anyhow;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+use crate::stats::Stats;
+
+/// What the manifest records about the capture besides its files
+pub struct CaptureInfo<'a> {
+    pub channels: &'a [String],
+    pub started: SystemTime,
+    pub stats: &'a Stats,
+}
+
+/// Write the manifest of `files` to `path`, through a temporary file renamed into place
+pub fn write(path: &str, files: &[PathBuf], info: CaptureInfo) -> anyhow::Result<()> {
+    let files = files
+        .iter()
+        .map(|file| {
+            let (size, sha256) = checksum(file)?;
+            Ok(json!({
+                "path": file.to_string_lossy(),
+                "size": size,
+                "sha256": sha256,
+            }))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let manifest = json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "started": humantime::format_rfc3339_micros(info.started).to_string(),
+        "stopped": humantime::format_rfc3339_micros(SystemTime::now()).to_string(),
+        "channels": info.channels,
+        "received": info.stats.received.load(Ordering::Relaxed),
+        "written": info.stats.written.load(Ordering::Relaxed),
+        "dropped": info.stats.dropped.load(Ordering::Relaxed),
+        "files": files,
+    });
+
+    let temporary = format!("{path}.tmp");
+    std::fs::write(&temporary, serde_json::to_string_pretty(&manifest)? + "\n")
+        .and_then(|_| std::fs::rename(&temporary, path))
+        .map_err(|err| anyhow!("Could not write manifest {path} with reason: {err}"))
+}
+
+fn checksum(path: &Path) -> anyhow::Result<(u64, String)> {
+    let mut file = File::open(path).map_err(|err| {
+        anyhow!(
+            "Could not read {} for the manifest with reason: {err}",
+            path.display()
+        )
+    })?;
+    let mut hasher = Sha256::new();
+    let size = std::io::copy(&mut file, &mut hasher)?;
+    let sha256 = hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
+    Ok((size, sha256))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksums_match_the_written_files() {
+        let dir = std::env::temp_dir().join(format!("zenoh-dump-{}-manifest", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let files = [
+            dir.join("capture.data.pcap"),
+            dir.join("capture.realtime.pcap"),
+        ];
+        std::fs::write(&files[0], b"abc").unwrap();
+        std::fs::write(&files[1], b"").unwrap();
+
+        let path = dir.join("manifest.json");
+        let path = path.to_str().unwrap();
+        let stats = Stats::new(["demo/**".to_string()]);
+        let info = CaptureInfo {
+            channels: &["demo/**".to_string()],
+            started: SystemTime::now(),
+            stats: &stats,
+        };
+        write(path, &files, info).unwrap();
+        assert!(!Path::new(&format!("{path}.tmp")).exists());
+
+        let manifest: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap();
+        assert_eq!(manifest["files"][0]["size"], 3);
+        assert_eq!(
+            manifest["files"][0]["sha256"],
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(manifest["files"][1]["size"], 0);
+        assert_eq!(
+            manifest["files"][1]["sha256"],
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(manifest["files"][1]["path"], files[1].to_str().unwrap());
+    }
+
+    #[test]
+    fn missing_file_fails_the_manifest() {
+        let dir =
+            std::env::temp_dir().join(format!("zenoh-dump-{}-no-manifest", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let stats = Stats::new([]);
+        let info = CaptureInfo {
+            channels: &[],
+            started: SystemTime::now(),
+            stats: &stats,
+        };
+        let path = dir.join("manifest.json");
+        assert!(write(path.to_str().unwrap(), &[dir.join("gone.pcap")], info).is_err());
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,193 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+/// Counters shared between the subscribers, the sink and the observability exporters
+pub struct Stats {
+    pub received: AtomicU64,
+    pub written: AtomicU64,
+    pub dropped: AtomicU64,
+    pub bytes: AtomicU64,
+    pub channels: Vec<(String, AtomicU64)>,
+}
+
+impl Stats {
+    pub fn new(channels: impl IntoIterator<Item = String>) -> Self {
+        Self {
+            received: AtomicU64::new(0),
+            written: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+            channels: channels
+                .into_iter()
+                .map(|channel| (channel, AtomicU64::new(0)))
+                .collect(),
+        }
+    }
+
+    pub fn record_received(&self, channel: usize) {
+        self.received.fetch_add(1, Ordering::Relaxed);
+        if let Some((_, count)) = self.channels.get(channel) {
+            count.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn record_written(&self, bytes: usize) {
+        self.written.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_dropped(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Per-key publish rates over a sliding window of one second buckets
+pub struct RateTracker {
+    startup_time: Instant,
+    window: u64,
+    max_keys: usize,
+    keys: HashMap<String, VecDeque<(u64, u64)>>,
+}
+
+impl RateTracker {
+    pub fn new(window: Duration, max_keys: usize) -> Self {
+        Self {
+            startup_time: Instant::now(),
+            window: window.as_secs().max(1),
+            max_keys,
+            keys: HashMap::new(),
+        }
+    }
+
+    pub fn record(&mut self, key: &str) {
+        let now = self.startup_time.elapsed().as_secs();
+        // Keys beyond the bound are not tracked
+        if !self.keys.contains_key(key) && self.keys.len() >= self.max_keys {
+            return;
+        }
+        let buckets = self.keys.entry(key.to_string()).or_default();
+        match buckets.back_mut() {
+            Some((second, count)) if *second == now => *count += 1,
+            _ => buckets.push_back((now, 1)),
+        }
+    }
+
+    /// The `n` keys with the highest rate in samples per second
+    pub fn top(&mut self, n: usize) -> Vec<(String, f64)> {
+        let oldest = self
+            .startup_time
+            .elapsed()
+            .as_secs()
+            .saturating_sub(self.window - 1);
+        let mut rates = Vec::with_capacity(self.keys.len());
+        self.keys.retain(|key, buckets| {
+            while buckets.front().is_some_and(|(second, _)| *second < oldest) {
+                buckets.pop_front();
+            }
+            let count: u64 = buckets.iter().map(|(_, count)| count).sum();
+            rates.push((key.clone(), count as f64 / self.window as f64));
+            !buckets.is_empty()
+        });
+        rates.sort_by(|a, b| b.1.total_cmp(&a.1));
+        rates.truncate(n);
+        rates
+    }
+}
+
+#[cfg(feature = "statsd")]
+pub mod statsd {
+    use std::{
+        net::UdpSocket,
+        sync::{Arc, atomic::Ordering},
+        time::Duration,
+    };
+
+    use anyhow::anyhow;
+    use tokio_util::sync::CancellationToken;
+
+    use super::Stats;
+
+    const PREFIX: &str = "zenoh_dump";
+
+    /// The socket metrics are sent to, resolving `target` so a typo fails before capturing
+    pub fn connect(target: &str) -> anyhow::Result<UdpSocket> {
+        let socket = UdpSocket::bind("0.0.0.0:0")
+            .map_err(|err| anyhow!("Could not open statsd socket with reason: {err}"))?;
+        socket
+            .connect(target)
+            .map_err(|err| anyhow!("Could not reach statsd at {target} with reason: {err}"))?;
+        Ok(socket)
+    }
+
+    /// Periodically push the counters as StatsD metrics over `socket`
+    pub fn spawn(
+        socket: UdpSocket,
+        interval: Duration,
+        stats: Arc<Stats>,
+        cancel_token: CancellationToken,
+    ) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            let mut last = Snapshot::default();
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = cancel_token.cancelled() => break,
+                }
+
+                let current = Snapshot::take(&stats);
+                for line in current.lines(&last, &stats) {
+                    // Metrics are best effort, a lost datagram is not worth reporting
+                    let _ = socket.send(line.as_bytes());
+                }
+                last = current;
+            }
+        })
+    }
+
+    #[derive(Default)]
+    struct Snapshot {
+        received: u64,
+        written: u64,
+        dropped: u64,
+        bytes: u64,
+        channels: Vec<u64>,
+    }
+
+    impl Snapshot {
+        fn take(stats: &Stats) -> Self {
+            Self {
+                received: stats.received.load(Ordering::Relaxed),
+                written: stats.written.load(Ordering::Relaxed),
+                dropped: stats.dropped.load(Ordering::Relaxed),
+                bytes: stats.bytes.load(Ordering::Relaxed),
+                channels: stats
+                    .channels
+                    .iter()
+                    .map(|(_, count)| count.load(Ordering::Relaxed))
+                    .collect(),
+            }
+        }
+
+        /// Counters are sent as the delta since the previous snapshot
+        fn lines(&self, last: &Snapshot, stats: &Stats) -> Vec<String> {
+            let mut lines = vec![
+                format!("{PREFIX}.received:{}|c", self.received - last.received),
+                format!("{PREFIX}.written:{}|c", self.written - last.written),
+                format!("{PREFIX}.dropped:{}|c", self.dropped - last.dropped),
+                format!("{PREFIX}.bytes:{}|c", self.bytes - last.bytes),
+            ];
+            for (index, (channel, _)) in stats.channels.iter().enumerate() {
+                let previous = last.channels.get(index).copied().unwrap_or(0);
+                lines.push(format!(
+                    "{PREFIX}.channel.received:{}|c|#channel:{channel}",
+                    self.channels[index] - previous
+                ));
+            }
+            lines
+        }
+    }
+}